@@ -2,5 +2,9 @@
 
 IMAGE_FILE=$1
 VIDEO_FILE=$2
+EVENT_ID=$3
+PART=$4
+PREVIEW_GIF=$5
+CONTACT_SHEET=$6
 # Do your own exciting stuff here
 echo $IMAGE_FILE
\ No newline at end of file
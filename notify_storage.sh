@@ -0,0 +1,6 @@
+#!/bin/bash
+
+EVENT=$1
+DETAIL=$2
+# Do your own exciting stuff here
+echo $EVENT $DETAIL
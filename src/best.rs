@@ -0,0 +1,116 @@
+use serde::Deserialize;
+
+use opencv::{prelude::*, Result};
+use opencv::core::{BORDER_DEFAULT, CV_64F, mean_std_dev, Rect, Size};
+use opencv::imgproc::{COLOR_BGR2GRAY, cvt_color, INTER_CUBIC, laplacian, resize};
+
+use crate::camera::Detection;
+use crate::recorder::write_image;
+
+const SHARPNESS_SCALE: f64 = 100.0;    // Laplacian variance giving a sharpness of 0.5
+const EDGE_MARGIN: i32 = 4;            // boxes this close to the edge are treated as clipped
+const CROP_MARGIN: f32 = 0.1;          // space left around the person in the crop
+
+/// How the best photo of a person event is chosen. Each term is roughly 0..1 before weighting.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BestImage {
+    #[serde(default = "default_weight")]
+    pub area_weight: f32,
+    #[serde(default = "default_weight")]
+    pub score_weight: f32,
+    #[serde(default = "default_weight")]
+    pub sharpness_weight: f32,
+    #[serde(default = "default_clipped_penalty")]
+    pub clipped_penalty: f32,       // subtracted when the box touches the edge of the detection area
+    #[serde(default = "default_crop")]
+    pub crop: bool,                 // also save an upscaled crop of the person
+    #[serde(default = "default_crop_height")]
+    pub crop_height: i32,
+}
+
+fn default_weight() -> f32 { 1.0 }
+fn default_clipped_penalty() -> f32 { 0.5 }
+fn default_crop() -> bool { true }
+fn default_crop_height() -> i32 { 512 }
+
+impl Default for BestImage
+{
+    fn default() -> Self
+    {
+        BestImage {
+            area_weight: default_weight(),
+            score_weight: default_weight(),
+            sharpness_weight: default_weight(),
+            clipped_penalty: default_clipped_penalty(),
+            crop: default_crop(),
+            crop_height: default_crop_height(),
+        }
+    }
+}
+
+impl BestImage
+{
+    /// Score a detection in a frame, higher is better. `bounds` is the area the model looked at.
+    pub fn score(&self, frame: &Mat, det: &Detection, bounds: Rect) -> f32
+    {
+        let r = det.rect;
+        let area = ((r.width * r.height) as f32) / ((bounds.width * bounds.height).max(1) as f32);
+
+        let sharpness = match sharpness(frame, r) {
+            Ok(v) => (v / (v + SHARPNESS_SCALE)) as f32,
+            Err(e) => { error!("Error measuring sharpness: {}", e); 0.0 }
+        };
+
+        let clipped = (r.x <= bounds.x + EDGE_MARGIN) || (r.y <= bounds.y + EDGE_MARGIN)
+            || (r.x + r.width >= bounds.x + bounds.width - EDGE_MARGIN)
+            || (r.y + r.height >= bounds.y + bounds.height - EDGE_MARGIN);
+
+        let mut score = self.area_weight * area.sqrt() + self.score_weight * det.score + self.sharpness_weight * sharpness;
+        if clipped { score -= self.clipped_penalty; }
+        score
+    }
+
+    /// Save the person cropped out of the frame and scaled up to `crop_height`
+    pub fn write_crop(&self, filename: &str, frame: &Mat, r: Rect) -> Result<bool>
+    {
+        let mx = ((r.width as f32) * CROP_MARGIN) as i32;
+        let my = ((r.height as f32) * CROP_MARGIN) as i32;
+        let r = clamp_rect(Rect::new(r.x - mx, r.y - my, r.width + 2 * mx, r.height + 2 * my), frame.size()?);
+        if r.width <= 0 || r.height <= 0 { return Ok(false); }
+
+        let person = Mat::roi(frame, r)?;
+        let scale = (self.crop_height as f64) / (r.height as f64);
+        let mut crop = Mat::default();
+        resize(&person, &mut crop, Size::new(((r.width as f64) * scale) as i32, self.crop_height), 0.0, 0.0, INTER_CUBIC)?;
+
+        write_image(filename, &crop)
+    }
+}
+
+// Variance of the Laplacian within the box, a blurred person gives a low value
+fn sharpness(frame: &Mat, r: Rect) -> Result<f64>
+{
+    let r = clamp_rect(r, frame.size()?);
+    if r.width <= 0 || r.height <= 0 { return Ok(0.0); }
+
+    let roi = Mat::roi(frame, r)?;
+    let mut gray = Mat::default();
+    cvt_color(&roi, &mut gray, COLOR_BGR2GRAY, 0)?;
+    let mut lap = Mat::default();
+    laplacian(&gray, &mut lap, CV_64F, 1, 1.0, 0.0, BORDER_DEFAULT)?;
+
+    let mut mean = Mat::default();
+    let mut stddev = Mat::default();
+    mean_std_dev(&lap, &mut mean, &mut stddev, &Mat::default())?;
+    let sd = *stddev.at::<f64>(0)?;
+    Ok(sd * sd)
+}
+
+fn clamp_rect(r: Rect, size: Size) -> Rect
+{
+    let x = r.x.max(0);
+    let y = r.y.max(0);
+    let x2 = (r.x + r.width).min(size.width);
+    let y2 = (r.y + r.height).min(size.height);
+    Rect::new(x, y, x2 - x, y2 - y)
+}
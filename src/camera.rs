@@ -1,24 +1,43 @@
-use std::time::SystemTime;
-use std::process::Command;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use std::thread;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::{Arc, mpsc, Mutex};
-use chrono::{DateTime, Local, Timelike};
+use chrono::{DateTime, Local};
 
 use serde::Deserialize;
 use opencv::{Error, highgui, prelude::*, Result, videoio};
 
 use moonfire_tflite::*;
-use opencv::core::{Rect, Scalar, Size, Vector};
+use opencv::core::{Rect, Scalar, Size};
 use opencv::imgproc::{INTER_AREA, line, rectangle, resize};
-use opencv::imgcodecs::imwrite;
-use opencv::videoio::{VideoCapture, VideoWriter};
+use opencv::videoio::VideoCapture;
+
+use crate::best::BestImage;
+use crate::continuous::{Continuous, ContinuousRecorder, SegmentLog};
+use crate::encryption;
+use crate::events::{self, Event};
+use crate::hooks::HookCommands;
+use crate::index;
+use crate::metadata::{EventMetadata, sidecar_filename};
+use crate::overlay::{original_filename, Overlay};
+use crate::preview::{Preview, PreviewBuilder};
+use crate::paths::{self, sanitise};
+use crate::retention::{Category, Retention};
+use crate::status::{self, CameraStatus};
+use crate::recorder::{close_part, close_writer, open_writer, recording_fps, StreamClock, TimedFrame, write_frame, write_image};
+use crate::storage::{self, StorageLevel, write_error};
+use crate::subtitles::{SubtitleTrack, Subtitles};
+use crate::throttle::Throttle;
+use crate::timelapse::{TimelapseRecorder, TimelapseSchedule};
+use crate::tracker::Tracker;
 
 const LINE_8: i32 = 8;
 
 const RESOLUTION: i32 = 320;  // input tensor resolution
 const THRESHOLD: f32 = 0.75;
 const MAX_BUFFER_FRAMES: usize = 15 * 120;
+const PERSON_LABEL: &str = "person";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Point {
@@ -36,21 +55,82 @@ impl Point
 
 type Polygon = Vec<Point>;
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Zone {
+    pub name: String,
+    pub boundary: Polygon,
+    #[serde(default)]
+    pub hooks: HookCommands,
+}
+
+#[derive(Debug, Clone)]
+pub struct Detection {
+    pub rect: Rect,
+    pub label: &'static str,
+    pub score: f32,
+    pub track_id: u32,
+    pub inside: bool,                   // inside the boundary, so it can trigger
+    pub zones: Vec<String>,
+}
+
+impl Detection
+{
+    // Move the box from the centre square to full frame coordinates
+    fn offset(&self, dx: i32) -> Detection
+    {
+        let mut det = self.clone();
+        det.rect.x += dx;
+        det
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Camera {
     pub name: String,
     pub source: String,
     pub timelapse: bool,
+    #[serde(default)]
+    pub timelapse_schedule: TimelapseSchedule,
     pub monitor: bool,
     pub boundary: Option<Polygon>,
+    pub zones: Option<Vec<Zone>>,
     pub trigger_frames: i32,
     pub trigger_distance: f32,
+    pub max_clip_seconds: Option<u64>,
+    pub merge_secs: Option<u64>,    // someone back within this of an event ending carries it on rather than starting a new one
+    pub overlay: Option<Overlay>,
+    pub subtitles: Option<Subtitles>,
+    pub preview: Option<Preview>,
+    #[serde(default)]
+    pub best_image: BestImage,
+    pub retention: Option<Retention>,
+    pub continuous: Option<Continuous>,
+    #[serde(default)]
+    pub hooks: HookCommands,
+    pub throttle: Option<Throttle>,
+}
+
+// One video file of a person event, long events are split into several parts
+struct Clip {
+    camera: String,
+    event_id: String,
+    part: u32,
+    start: f64,         // timestamp of the trigger, or of the split for continuation clips
+    video_filename: String,
+    first_image: String,
+    fps: f64,
+    size: Size,
+    overlay: Option<Overlay>,
+    subtitles: Option<Subtitles>,
+    preview: Option<Preview>,
+    best_image: BestImage,
+    snapshots_only: bool,   // short of disk space, just write the photos
+    segments: Option<SegmentLog>,   // the camera records continuously, so the event is indexed rather than copied
 }
 
-#[derive(Clone)]
 enum FrameSend {
-    Frame(Mat),
-    Best(Mat, String),
+    Frame(TimedFrame),
+    Best(TimedFrame, String, Rect),
     End,
 }
 
@@ -61,14 +141,35 @@ impl Camera
             name: "Security Camera".to_string(),
             source: url.to_string(),
             timelapse: false,
+            timelapse_schedule: TimelapseSchedule::default(),
             monitor: true,
             boundary: None,
+            zones: None,
             trigger_frames: 1,
             trigger_distance: 0.0,
+            max_clip_seconds: None,
+            merge_secs: None,
+            overlay: None,
+            subtitles: None,
+            preview: None,
+            best_image: BestImage::default(),
+            retention: None,
+            continuous: None,
+            hooks: HookCommands::new(),
+            throttle: None,
         }
     }
 
-    pub fn run(&self, interpreter: Arc<Mutex<Interpreter>>, notify_start_person: bool, notify_end_person: bool, notify_timelapse_rollover: bool) -> Result<()>
+    /// Check the settings when the config is loaded
+    pub fn check(&self) -> Result<(), String>
+    {
+        if self.max_clip_seconds == Some(0) { return Err("max_clip_seconds should be at least 1".to_string()); }
+        if let Some(preview) = &self.preview { preview.check()?; }
+        if let Some(retention) = &self.retention { retention.check()?; }
+        Ok(())
+    }
+
+    pub fn run(&self, interpreter: Arc<Mutex<Interpreter>>) -> Result<()>
     {
         if self.monitor
         {
@@ -87,8 +188,11 @@ impl Camera
             error!("{}: Can't open video stream !", &self.name);
             return Err(Error::new(-1, "Camera aborted"));
         }
+        events::publish(Event::CameraOnline { camera: self.name.clone() });
 
         // Initialisation
+        let mut clock = StreamClock::new();
+        let nominal_fps = cam.get(videoio::CAP_PROP_FPS).unwrap_or(0.0);
         let mut frame = Mat::default();
         let mut frame320 = Mat::default();
         let size320 = Size::new(RESOLUTION, RESOLUTION);
@@ -113,33 +217,47 @@ impl Camera
         let mut tick = SystemTime::now();
         let mut frames = 0;
         let mut fps = 0.0;
+        let mut people = 0;
         let mut frames_minute = 0;
         let mut elapsed_seconds = 0;
         let mut frame_monitoring_interval = 300;
 
 
         // Timelapse recording
-        let mut timelapse_filename = "".to_string();
-        let mut timelapse: VideoWriter = VideoWriter::default()?;
-        let mut skip_timlapse = false;
+        let mut timelapse = None;
         if self.timelapse
         {
             info!("{}: Timelapse recording is enabled.", &self.name);
-            timelapse_filename = format!("captures/timelapse/{}{}.mp4", &self.name, timestamp_string());
-            timelapse = create_video_writer(&timelapse_filename, 1.5, fsize);
+            timelapse = Some(TimelapseRecorder::start(&self.name, &self.timelapse_schedule, &self.overlay, fsize, &Local::now()));
         } else { info!("{}: Timelapse recording is disabled.", &self.name); }
 
+        // Continuous recording
+        let mut continuous = None;
+        if let Some(settings) = &self.continuous
+        {
+            info!("{}: Continuous recording in {}s segments.", &self.name, settings.segment_seconds);
+            continuous = Some(ContinuousRecorder::start(&self.name, settings, &self.overlay, recording_fps(nominal_fps, 0.0), fsize));
+        }
+        let segments = continuous.as_ref().map(|c| c.log.clone());
+
         // Person recording
         let buffer_size = 150;
         let mut buffer_pnt = 0;
-        let mut buffer: Vec<Mat> = Vec::with_capacity(buffer_size);    /* Cyclic buffer for 10 seconds prior to detection */
+        let mut buffer: Vec<TimedFrame> = Vec::with_capacity(buffer_size);    /* Cyclic buffer for 10 seconds prior to detection */
         let mut person_recording = false;
-        let mut person_best_size = 0;
+        let mut person_best_score = f32::MIN;
         let mut person_last_seen = SystemTime::now();
+        let mut person_ended: Option<SystemTime> = None;
+        let mut person_event_id = String::new();
+        let mut person_first_image = String::new();
+        let mut person_part = 0;
+        let mut person_clip_start = 0.0;
+        let mut person_fps = 0.0;
         let mut person_trigger_frames_person = 0;
         let mut person_trigger_distance = 0.0;
         let mut person_trigger_last_x = 0;
         let mut person_trigger_last_y = 0;
+        let mut tracker = Tracker::new();
 
 
         // Channel to send frames
@@ -150,6 +268,7 @@ impl Camera
             let rs = cam.read(&mut frame);
             match rs {
                 Ok(true) => {
+                    let pts = clock.stamp(&cam);
                     if frame.size()?.width > 0 {
                         let frame320rc = frame.col_bounds(fx, fw);
                         match frame320rc {
@@ -157,90 +276,124 @@ impl Camera
                                 resize(&frame320rc, &mut frame320, size320, 0.0, 0.0, INTER_AREA);
 
                                 // Call the interpreter
-                                let person = person_in_frame(&interpreter, &frame320, d);
-                                if let Some(r) = person
+                                let mut detections = people_in_frame(&interpreter, &frame320, d);
+                                tracker.update(&mut detections);
+                                let outside_color = Scalar::from((64.0, 64.0, 240.0));
+                                let inside_color = Scalar::from((64.0, 240.0, 64.0));
+
+                                // The first person inside the boundary drives the trigger, zones are only recorded
+                                let mut person = None;
+                                people = 0;
+                                for (i, det) in detections.iter_mut().enumerate()
                                 {
-                                    let outside_color = Scalar::from((64.0, 64.0, 240.0));
-                                    let inside_color = Scalar::from((64.0, 240.0, 64.0));
-
+                                    let r = det.rect;
                                     let centre = Point::new(r.x + r.width / 2, r.y + r.height / 2);
-
-                                    if inside_polygon(&self.boundary, &centre)
+                                    det.inside = self.boundary.as_ref().is_none_or(|b| inside_polygon(b, &centre));
+                                    if det.inside { det.zones = self.zones_at(&centre); }
+                                    if self.monitor
                                     {
-                                        if self.monitor
-                                        {
-                                            rectangle(&mut frame320rc, r, inside_color, 2, LINE_8, 0);
-                                        }
+                                        let color = if det.inside { inside_color } else { outside_color };
+                                        rectangle(&mut frame320rc, r, color, 2, LINE_8, 0);
+                                    }
+                                    if det.inside
+                                    {
+                                        people += 1;
+                                        if person.is_none() { person = Some((i, r)); }
+                                    }
+                                }
 
-                                        person_last_seen = SystemTime::now();
-                                        let area = r.height * r.width;
-                                        if area > person_best_size
+                                // Recordings use full frame coordinates
+                                let detections: Vec<Detection> = detections.iter().map(|det| det.offset(fx)).collect();
+
+                                if let Some((i, r)) = person
+                                {
+                                    let centre = Point::new(r.x + r.width / 2, r.y + r.height / 2);
+                                    person_last_seen = SystemTime::now();
+                                    if person_recording
+                                    {
+                                        let bounds = Rect::new(fx, 0, fw - fx, fsize.height);
+                                        let score = self.best_image.score(&frame, &detections[i], bounds);
+                                        if score > person_best_score
                                         {
-                                            person_best_size = area;
-                                            let person_best_frame = frame.clone();
+                                            person_best_score = score;
+                                            let person_best_frame = timed_frame(&frame, pts, &clock, &detections);
                                             let person_best_time = timestamp_string();
 
+                                            events::publish(Event::BestImageUpdated {
+                                                camera: self.name.clone(),
+                                                event_id: person_event_id.clone(),
+                                                part: person_part,
+                                                time: person_best_frame.time.to_rfc3339(),
+                                                score: detections[i].score,
+                                            });
                                             match &sync_sender
                                             {
-                                                Some(tx) => { tx.send(FrameSend::Best(person_best_frame, person_best_time)); }
+                                                Some(tx) => { tx.send(FrameSend::Best(person_best_frame, person_best_time, detections[i].rect)); }
                                                 None => {}
                                             }
                                         }
+                                    }
 
-                                        person_trigger_frames_person += 1;
-                                        if (person_trigger_last_x == 0) && (person_trigger_last_y == 0)
+                                    person_trigger_frames_person += 1;
+                                    if (person_trigger_last_x == 0) && (person_trigger_last_y == 0)
+                                    {
+                                        person_trigger_last_x = centre.x;
+                                        person_trigger_last_y = centre.y;
+                                    }
+                                    let dx = (centre.x - person_trigger_last_x) as f32;
+                                    let dy = (centre.y - person_trigger_last_y) as f32;
+                                    person_trigger_distance += f32::sqrt(dx * dx + dy * dy);
+
+                                    if !person_recording && (person_trigger_frames_person > self.trigger_frames) && (person_trigger_distance > self.trigger_distance)
+                                        && status::armed(&self.name)
+                                    {
+                                        // Start recording, someone back soon after the last event ended carries it on in a new part
+                                        person_recording = true;
+                                        let merge = match (self.merge_secs, person_ended) {
+                                            (Some(secs), Some(ended)) => SystemTime::now().duration_since(ended).unwrap_or_default().as_secs() <= secs,
+                                            _ => false,
+                                        };
+                                        if merge
                                         {
-                                            person_trigger_last_x = centre.x;
-                                            person_trigger_last_y = centre.y;
+                                            person_part += 1;
+                                            info!("{}: Person back, continuing {} in part {}", &self.name, &person_event_id, person_part);
                                         }
-                                        let dx = (centre.x - person_trigger_last_x) as f32;
-                                        let dy = (centre.y - person_trigger_last_y) as f32;
-                                        person_trigger_distance += f32::sqrt(dx * dx + dy * dy);
-
-                                        if !person_recording && (person_trigger_frames_person > self.trigger_frames) && (person_trigger_distance > self.trigger_distance)
+                                        else
                                         {
-                                            // Start recording
                                             info!("Person detected - recording started to buffer");
-                                            person_recording = true;
-
-                                            // start the async writer
-                                            let (tx, rx) = mpsc::channel();
-
-                                            let video_filename = format!("captures/people/video/{}{}.mp4", self.name, timestamp_string());
-                                            let image_filename = format!("captures/people/photos/{}{}-first.jpg", self.name, timestamp_string());
-                                            async_writer(rx, video_filename, image_filename.clone(), fps, fsize, notify_end_person, self.name.clone());
+                                            person_event_id = format!("{}{}", sanitise(&self.name), timestamp_string());
+                                            person_first_image = paths::file(Category::PeoplePhotos, &self.name, &Local::now(), &format!("{}-first.jpg", person_event_id));
+                                            person_part = 1;
+                                        }
+                                        person_clip_start = pts;
+                                        person_fps = recording_fps(nominal_fps, fps);
 
-                                            // Write the cyclic buffer frames
-                                            for _ in buffer_pnt..(buffer.len() - 1)
-                                            {
-                                                let f = buffer.remove(buffer_pnt);
-                                                tx.send(FrameSend::Frame(f));
-                                            }
-                                            if buffer_pnt > 0 {
-                                                for _ in 0..(buffer_pnt - 1)
-                                                {
-                                                    let f = buffer.remove(0);
-                                                    tx.send(FrameSend::Frame(f));
-                                                }
-                                            }
-                                            buffer_pnt = 0;
-                                            sync_sender = Some(tx);
+                                        // start the async writer
+                                        let tx = self.start_clip(&person_event_id, person_part, pts, &person_first_image, person_fps, fsize, &segments);
 
-                                            // Write first photo and call notifier
-                                            let flags = Vector::new();
-                                            imwrite(&image_filename, &frame, &flags);
-                                            if notify_start_person
-                                            {
-                                                info!("Calling 'notify_start_person.sh {}'", &image_filename);
-                                                let r = Command::new("./notify_start_person.sh")
-                                                    .arg(&image_filename).spawn();
-                                                if let Err(e) = r { error!("Error calling script: {}", e) }
-                                            }
+                                        // Write the cyclic buffer frames, oldest first
+                                        buffer.rotate_left(buffer_pnt);
+                                        for f in buffer.drain(..)
+                                        {
+                                            tx.send(FrameSend::Frame(f));
                                         }
-                                    } else {
-                                        if self.monitor
+                                        buffer_pnt = 0;
+                                        sync_sender = Some(tx);
+
+                                        // Write first photo and tell the sinks, unless the event has already started
+                                        if !merge
                                         {
-                                            rectangle(&mut frame320rc, r, outside_color, 2, LINE_8, 0);
+                                            let first = timed_frame(&frame, pts, &clock, &detections);
+                                            self.write_snapshot(&person_first_image, &first);
+                                            events::publish(Event::Started {
+                                                camera: self.name.clone(),
+                                                event_id: person_event_id.clone(),
+                                                time: first.time.to_rfc3339(),
+                                                first_image: person_first_image.clone(),
+                                                zones: detections[i].zones.clone(),
+                                                label: detections[i].label.to_string(),
+                                                score: detections[i].score,
+                                            });
                                         }
                                     }
                                 }
@@ -251,10 +404,25 @@ impl Camera
                                 {
                                     let elapsed = SystemTime::now().duration_since(person_last_seen).unwrap().as_millis();
 
+                                    let clip_full = match self.max_clip_seconds {
+                                        Some(max) => pts - person_clip_start >= Duration::from_secs(max).as_millis() as f64,
+                                        None => false,
+                                    };
+                                    if clip_full && elapsed <= 30000
+                                    {
+                                        // Close this part and carry on recording the event in a continuation clip
+                                        if let Some(tx) = &sync_sender { tx.send(FrameSend::End); }
+                                        person_part += 1;
+                                        person_clip_start = pts;
+                                        person_best_score = f32::MIN;
+                                        info!("{}: Maximum clip length reached, continuing {} in part {}", &self.name, &person_event_id, person_part);
+                                        sync_sender = Some(self.start_clip(&person_event_id, person_part, pts, &person_first_image, person_fps, fsize, &segments));
+                                    }
+
                                     match &sync_sender
                                     {
                                         Some(tx) => {
-                                            if elapsed > 30000 { tx.send(FrameSend::End); } else { tx.send(FrameSend::Frame(frame.clone())); }
+                                            if elapsed > 30000 { tx.send(FrameSend::End); } else { tx.send(FrameSend::Frame(timed_frame(&frame, pts, &clock, &detections))); }
                                         }
                                         None => { error!("sync_sender is none."); }
                                     }
@@ -263,16 +431,42 @@ impl Camera
                                     {
                                         // Finish the async writing
                                         person_recording = false;
-                                        person_best_size = 0;
+                                        person_ended = Some(SystemTime::now());
+                                        person_best_score = f32::MIN;
                                         buffer_pnt = 0;
                                     }
                                 } else {
                                     // Stash the frame in the buffer
-                                    if buffer.len() <= buffer_pnt { buffer.push(frame.clone()); } else { buffer[buffer_pnt] = frame.clone(); }
+                                    let f = timed_frame(&frame, pts, &clock, &detections);
+                                    if buffer.len() <= buffer_pnt { buffer.push(f); } else { buffer[buffer_pnt] = f; }
                                     buffer_pnt = buffer_pnt + 1;
                                     if buffer_pnt == buffer_size { buffer_pnt = 0 };
                                 }
 
+                                // Continuous recording keeps going until the disk is almost full
+                                if let Some(continuous) = &continuous
+                                {
+                                    if storage::level() < StorageLevel::SnapshotsOnly
+                                    {
+                                        continuous.write(timed_frame(&frame, pts, &clock, &detections));
+                                    }
+                                }
+
+                                // Timelapse recording
+                                if let Some(timelapse) = &mut timelapse
+                                {
+                                    if let Some(closed) = timelapse.rollover(&clock.time(pts))
+                                    {
+                                        events::publish(Event::TimelapseRollover { camera: self.name.clone(), video: closed });
+                                    }
+
+                                    // Write timelapse frame, unless the disk is nearly full
+                                    if timelapse.due(pts, &detections) && storage::level() < StorageLevel::NoTimelapse
+                                    {
+                                        timelapse.write(timed_frame(&frame, pts, &clock, &detections));
+                                    }
+                                }
+
                                 // Time related
                                 frames += 1;
                                 frames_minute += 1;
@@ -283,6 +477,7 @@ impl Camera
                                     // Update fps
                                     fps = (frames as f64) / ((elapsed as f64) / 1000.0);
                                     frames = 0;
+                                    status::update(&self.name, CameraStatus { people, fps });
                                     elapsed_seconds += 1;
                                     if elapsed_seconds >= frame_monitoring_interval // 5 minutes
                                     {
@@ -301,37 +496,15 @@ impl Camera
                                     person_trigger_last_y = 0;
 
                                     tick = SystemTime::now();
-
-                                    if self.timelapse
-                                    {
-                                        // Write timelapse frame
-                                        timelapse.write(&frame)?;
-
-                                        // Rollover timelapse file
-                                        let time = Local::now();
-                                        if (time.second() == 0) && (time.minute() == 0) && (!skip_timlapse)
-                                        {
-                                            skip_timlapse = true;
-                                            timelapse.release()?;
-
-                                            if notify_timelapse_rollover
-                                            {
-                                                // Call the notify script
-                                                info!("Calling 'notify_timelapse_rollover.sh {}'", &timelapse_filename);
-                                                let r = Command::new("./notify_timelapse_rollover.sh")
-                                                    .arg(&timelapse_filename).spawn();
-                                                if let Err(e) = r { error!("Error calling script: {}", e) }
-                                            }
-
-                                            timelapse_filename = format!("captures/timelapse/{}{}.mp4", &self.name, timestamp_string());
-                                            timelapse = create_video_writer(&timelapse_filename, 1.5, fsize);
-                                        } else { skip_timlapse = false; }
-                                    }
                                 }
 
                                 if self.monitor
                                 {
                                     if let Some(polygon) = &self.boundary { draw_boundary(polygon, &mut frame320rc); }
+                                    if let Some(zones) = &self.zones
+                                    {
+                                        for zone in zones { draw_boundary(&zone.boundary, &mut frame320rc); }
+                                    }
                                     highgui::imshow(&self.name, &mut frame)?;
                                 }
                             }
@@ -341,10 +514,7 @@ impl Camera
                     if self.monitor
                     {
                         let key = highgui::wait_key(5)?;
-                        if key > 0 && key != 255 {
-                            timelapse.release()?;
-                            break;
-                        }
+                        if key > 0 && key != 255 { break; }
                     }
                 }
                 _ => {
@@ -355,67 +525,277 @@ impl Camera
         }
         Ok(())
     }
+
+    // Start the writer thread for one clip of a person event
+    #[allow(clippy::too_many_arguments)]
+    fn start_clip(&self, event_id: &str, part: u32, start: f64, first_image: &str, fps: f64, size: Size, segments: &Option<SegmentLog>) -> Sender<FrameSend>
+    {
+        let (tx, rx) = mpsc::channel();
+
+        // Parts are only numbered when clips may be split or events merged
+        let name = match self.max_clip_seconds.is_some() || self.merge_secs.is_some() {
+            true => format!("{}-{:03}.mp4", event_id, part),
+            false => format!("{}.mp4", event_id),
+        };
+        let video_filename = paths::file(Category::PeopleVideo, &self.name, &Local::now(), &name);
+
+        let clip = Clip {
+            camera: self.name.clone(),
+            event_id: event_id.to_string(),
+            part,
+            start,
+            video_filename,
+            first_image: first_image.to_string(),
+            fps,
+            size,
+            overlay: self.overlay.clone(),
+            subtitles: self.subtitles.clone(),
+            preview: self.preview.clone(),
+            best_image: self.best_image.clone(),
+            snapshots_only: storage::level() == StorageLevel::SnapshotsOnly,
+            segments: segments.clone(),
+        };
+        if clip.snapshots_only { warn!("{}: Short of disk space, recording {} as snapshots only.", &self.name, &clip.event_id); }
+        async_writer(rx, clip);
+        tx
+    }
+
+    // Names of the zones containing a point inside the boundary, a camera without zones has one zone named after itself
+    fn zones_at(&self, point: &Point) -> Vec<String>
+    {
+        match &self.zones
+        {
+            Some(zones) => {
+                zones.iter()
+                    .filter(|zone| inside_polygon(&zone.boundary, point))
+                    .map(|zone| zone.name.clone())
+                    .collect()
+            }
+            None => vec![self.name.clone()]
+        }
+    }
+
+    fn write_snapshot(&self, filename: &str, f: &TimedFrame)
+    {
+        write_snapshot(filename, f, &self.overlay, &self.name);
+    }
 }
 
     // Write the frames in a separate thread
 //    - doing this in the main thread causes stalls on the input stream
-    fn async_writer(rx: Receiver<FrameSend>, video_filename: String, image_filename: String, fps: f64, fsize: Size, notify_end_person: bool, camera_name: String)
+    fn async_writer(rx: Receiver<FrameSend>, clip: Clip)
     {
         let rx = Arc::new(Mutex::new(rx));
         thread::spawn(move || {
             let rx = rx.lock().unwrap();
-            let mut best_frame = Mat::default();
+            let mut best_frame = None;
+            let mut best_rect = Rect::default();
             let mut best_time = String::default();
-            let mut have_best = false;
-
-            let mut person_writer = create_video_writer(&video_filename, fps, fsize);
+            // no video is written when short of disk space or when the camera records continuously,
+            // the sidecar and notifier are given an empty name
+            let record_video = !clip.snapshots_only && clip.segments.is_none();
+            let video = match record_video {
+                true => clip.video_filename.clone(),
+                false => String::new(),
+            };
+            let mut span = None;
+            let mut metadata = EventMetadata::new(&clip.camera, &clip.event_id, clip.part, &video, &clip.first_image);
+            let mut first_pts = None;
+            let mut frame_index = 0;
+            let mut subtitle_track = SubtitleTrack::new();
+            let mut offset = 0.0;
+            let mut preview = clip.preview.as_ref().map(|p| PreviewBuilder::new(p, clip.size));
+
+            let original_video = original_filename(&clip.video_filename);
+            let mut person_writer = None;
+            let mut original_writer = None;
+            if record_video
+            {
+                person_writer = open_writer(&clip.video_filename, clip.fps, clip.size, 1000.0 / clip.fps);
+                if let Some(overlay) = &clip.overlay
+                {
+                    if overlay.keep_original { original_writer = open_writer(&original_video, clip.fps, clip.size, 1000.0 / clip.fps); }
+                }
+            }
             loop
             {
-                let r = rx.recv();
-                if let Ok(r) = r {
-                    match r {
-                        FrameSend::Frame(f) => { person_writer.write(&f).unwrap(); }
-                        FrameSend::Best(fm, timestamp) => {
-                            best_frame = fm;
-                            best_time = timestamp;
-                            have_best = true;
+                // The camera dropping out mid event ends the clip just as the event ending does
+                match rx.recv() {
+                    Ok(FrameSend::Frame(f)) => {
+                        let start = *first_pts.get_or_insert(f.pts);
+                        metadata.add_frame(frame_index, start, &f);
+                        span = Some((span.map_or(f.time, |(first, _)| first), f.time));
+                        frame_index += 1;
+                        offset = (f.pts - start) / 1000.0;
+                        subtitle_track.add_frame(offset, &f.detections);
+
+                        match &clip.overlay
+                        {
+                            Some(overlay) => {
+                                let annotated = overlay.render(&clip.camera, &f);
+                                if let Some(preview) = &mut preview { preview.add_frame(&annotated, f.pts); }
+                                write_frame(&mut person_writer, annotated, f.pts, &clip.video_filename);
+                                write_frame(&mut original_writer, f.frame, f.pts, &original_video);
+                            }
+                            None => {
+                                if let Some(preview) = &mut preview { preview.add_frame(&f.frame, f.pts); }
+                                write_frame(&mut person_writer, f.frame, f.pts, &clip.video_filename);
+                            }
                         }
-                        FrameSend::End => { break; }
                     }
+                    Ok(FrameSend::Best(fm, timestamp, rect)) => {
+                        if let Some(preview) = &mut preview { preview.best(); }
+                        best_rect = rect;
+                        best_frame = Some(fm);
+                        best_time = timestamp;
+                    }
+                    Ok(FrameSend::End) | Err(_) => { break; }
                 }
             }
 
-            person_writer.release().unwrap();
+            let video_part = close_part(&mut person_writer, &clip.video_filename);
+            close_writer(&mut original_writer, &original_video);
+
+            // write the captions, muxing them in before the video is finalised
+            if let Some(subtitles) = &clip.subtitles
+            {
+                if record_video
+                {
+                    subtitle_track.finish(offset + 1.0 / clip.fps);
+                    subtitle_track.write(&clip.video_filename, subtitles, video_part);
+                }
+            }
+            if video_part
+            {
+                if let Err(e) = storage::finalise(&clip.video_filename) { write_error(&clip.video_filename, &e); }
+            }
 
             // write the best frame
-            let filename = format!("captures/people/photos/{}{}-best.jpg", camera_name, best_time);
-            if have_best
+            let filename = paths::file(Category::PeoplePhotos, &clip.camera, &Local::now(), &format!("{}{}-best.jpg", clip.camera, best_time));
+            let have_best = best_frame.is_some();
+            if let Some(best_frame) = &best_frame
             {
-                let flags = Vector::new();
-                imwrite(&filename, &best_frame, &flags);
+                write_snapshot(&filename, best_frame, &clip.overlay, &clip.camera);
+                if clip.best_image.crop
+                {
+                    let crop_filename = paths::file(Category::PeoplePhotos, &clip.camera, &Local::now(), &format!("{}{}-best-crop.jpg", clip.camera, best_time));
+                    match clip.best_image.write_crop(&crop_filename, &best_frame.frame, best_rect)
+                    {
+                        Ok(_) => {}
+                        Err(e) => { write_error(&crop_filename, &e); }
+                    }
+                }
+            }
+
+            // write the previews
+            let mut gif_filename = String::new();
+            let mut sheet_filename = String::new();
+            if let Some(preview) = &preview
+            {
+                let stem = clip_stem(&clip.video_filename);
+                let filename = paths::file(Category::PeoplePhotos, &clip.camera, &Local::now(), &format!("{}-preview.gif", stem));
+                match preview.write_gif(&filename)
+                {
+                    Ok(true) => { gif_filename = filename; }
+                    Ok(false) => {}
+                    Err(e) => { write_error(&filename, &e); }
+                }
+                let filename = paths::file(Category::PeoplePhotos, &clip.camera, &Local::now(), &format!("{}-contact.jpg", stem));
+                match preview.write_contact_sheet(&filename)
+                {
+                    Ok(true) => { sheet_filename = filename; }
+                    Ok(false) => {}
+                    Err(e) => { write_error(&filename, &e); }
+                }
             }
 
-            info!("Person recording finished.");
+            info!("Person recording finished: {} part {}.", &clip.event_id, clip.part);
 
-            // Call the notifier
-            if notify_end_person
+            // write the sidecar
+            if let Some(first_pts) = first_pts
             {
-                let image = match have_best {
-                    true => { filename }
-                    false => { image_filename }
-                };
-
-                info!("Calling 'notify_end_person.sh {} {}'", &image, &video_filename);
-                let r = Command::new("./notify_end_person.sh")
-                    .arg(image).arg(&video_filename).spawn();
-                if let Err(e) = r { error!("Error calling script: {}", e) }
+                metadata.pre_roll_seconds = ((clip.start - first_pts) / 1000.0).max(0.0);
             }
+            if have_best { metadata.best_image = Some(filename.clone()); }
+            if !gif_filename.is_empty() { metadata.preview_gif = Some(gif_filename.clone()); }
+            if !sheet_filename.is_empty() { metadata.contact_sheet = Some(sheet_filename.clone()); }
+            if let (Some(segments), Some((first, last))) = (&clip.segments, span)
+            {
+                metadata.segments = segments.ranges(first, last);
+            }
+            let sidecar = sidecar_filename(&clip.video_filename);
+            if let Err(e) = metadata.write(&sidecar) { write_error(&sidecar, &e); }
+            index::record_event(&metadata, &sidecar);
+
+            // Tell the sinks
+            let image = match have_best {
+                true => { filename }
+                false => { clip.first_image }
+            };
+            events::publish(Event::Ended {
+                camera: clip.camera.clone(),
+                event_id: clip.event_id.clone(),
+                part: clip.part,
+                start: metadata.start.clone(),
+                end: metadata.end.clone(),
+                video,
+                best_image: image,
+                preview_gif: gif_filename,
+                contact_sheet: sheet_filename,
+                sidecar,
+                zones: metadata.zones.iter().cloned().collect(),
+                labels: metadata.labels().into_iter().collect(),
+                max_score: metadata.max_score(),
+            });
         });
     }
 
 
-fn person_in_frame(int_mutex: &Arc<Mutex<Interpreter>>, frame320: &Mat, d: f32) -> Option<Rect>
+fn timed_frame(frame: &Mat, pts: f64, clock: &StreamClock, detections: &[Detection]) -> TimedFrame
+{
+    TimedFrame {
+        frame: frame.clone(),
+        pts,
+        time: clock.time(pts),
+        detections: detections.to_vec(),
+    }
+}
+
+// Name of a clip without the directory or extension, e.g. `Door20230519-101500-002`
+fn clip_stem(video_filename: &str) -> String
+{
+    Path::new(encryption::plain_name(video_filename)).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+// Write a photo with the overlay burned in, keeping the unannotated original if required
+fn write_snapshot(filename: &str, f: &TimedFrame, overlay: &Option<Overlay>, camera: &str)
+{
+    match overlay
+    {
+        Some(overlay) => {
+            save_image(filename, &overlay.render(camera, f));
+            if overlay.keep_original { save_image(&original_filename(filename), &f.frame); }
+        }
+        None => { save_image(filename, &f.frame); }
+    }
+}
+
+// Write a photo, reporting any failure
+fn save_image(filename: &str, image: &Mat)
+{
+    match write_image(filename, image)
+    {
+        Ok(true) => {}
+        Ok(false) => { write_error(filename, &"image not written"); }
+        Err(e) => { write_error(filename, &e); }
+    }
+}
+
+
+fn people_in_frame(int_mutex: &Arc<Mutex<Interpreter>>, frame320: &Mat, d: f32) -> Vec<Detection>
 {
+    let mut people = Vec::new();
+
     let mut interpreter = int_mutex.lock().unwrap();
 
     // Create input tensor
@@ -460,59 +840,38 @@ fn person_in_frame(int_mutex: &Arc<Mutex<Interpreter>>, frame320: &Mat, d: f32)
                 width: w - x,
                 height: h - y,
             };
-            return Some(r);
+            people.push(Detection { rect: r, label: PERSON_LABEL, score: ot[2].f32s()[i], track_id: 0, inside: false, zones: vec![] });
         }
     }
-    None
+    people
 }
 
 
-    fn create_video_writer(filename: &str, fps: f64, size: Size) -> VideoWriter
+    fn inside_polygon(polygon: &[Point], point: &Point) -> bool
     {
-        let fourcc = VideoWriter::fourcc('m' as i8, 'p' as i8, '4' as i8, 'v' as i8).expect("Invalid video fourcc");
-        let writer = VideoWriter::new(&filename, fourcc, fps, size, true);
-        match writer {
-            Ok(writer) => {
-                info!("Creating new video file: {}", filename);
-                writer
-            }
-            Err(e) => {
-                error!("Can't create video writer {}", filename);
-                error!("Error: {}", e.message);
-                panic!("{}", e);
-            }
-        }
-    }
+        let mut inside = false;
 
+        let mut j = match polygon.last() {
+            Some(j) => j,
+            None => { return false; }
+        };
 
-    fn inside_polygon(polygon: &Option<Vec<Point>>, point: &Point) -> bool
-    {
-        match polygon
+        for i in polygon
         {
-            Some(polygon) => {
-                let mut inside = false;
-
-                let mut j = polygon.last().unwrap();
-
-                for i in polygon
+            if (i.y < point.y) && (j.y >= point.y) || (j.y < point.y) && (i.y >= point.y)
+            {
+                if i.x + (point.y - i.y) / (j.y - i.y) * (j.x - i.x) < point.x
                 {
-                    if (i.y < point.y) && (j.y >= point.y) || (j.y < point.y) && (i.y >= point.y)
-                    {
-                        if i.x + (point.y - i.y) / (j.y - i.y) * (j.x - i.x) < point.x
-                        {
-                            inside = !inside;
-                        }
-                    }
-                    j = i;
+                    inside = !inside;
                 }
-
-                inside
-            },
-            None => true
+            }
+            j = i;
         }
+
+        inside
     }
 
-    fn draw_boundary(polygon: &Vec<Point>, frame: &mut Mat)
+    fn draw_boundary(polygon: &[Point], frame: &mut Mat)
     {
         let mut last = None;
         let color = Scalar::from((128.0, 192.0, 192.0));
@@ -2,12 +2,24 @@ use std::fs;
 use std::path::Path;
 use serde::Deserialize;
 use crate::camera::Camera;
+use crate::email::Email;
+use crate::encryption::Encryption;
+use crate::hooks::HooksConfig;
+use crate::mqtt::Mqtt;
+use crate::paths::{default_storage_root, PathTemplates};
+use crate::storage::StorageConfig;
+use crate::webhook::Webhook;
 
-pub const USAGE: &'static str = "
+pub const USAGE: &str = "
 security_camera
 Person activated camera video stream monitoring and recording
 
 Usage:
+  security_camera export [options] <camera> <start> <end>
+  security_camera events [options]
+  security_camera decrypt [options] <file>...
+  security_camera export-evidence [options] <event-id>...
+  security_camera verify [options] <bundle>
   security_camera [options] <video-source>
   security_camera [options]
   security_camera (-h | --help)
@@ -15,15 +27,42 @@ Usage:
 Options:
   -h --help                         Show this screen
   -m --monitor                      Create monitor window showing real time feed
-  -t --timelapse                    Record timelapse files, continuous 1 fps with hourly rollover
+  -t --timelapse                    Record timelapse files of <video-source>, hourly at 1 fps
   -p --polygon <polygon-file>       Use a boundary polygon, polygon file is csv with one point per line
   -c --config <config-file>         Use a config file (for multiple camera monitoring)
+  -o --output <file>                Export: video file to write, default is <camera><start>-export.mp4
+                                    Evidence: bundle directory to write, default is evidence-<event-id>
+  --from <recordings>               Export: 'continuous' or 'timelapse', default is continuous if there are any
+  --overlay                         Export: burn the date/time and camera name into the video
+  --camera <name>                   Events: only this camera
+  --zone <zone>                     Events: only events in this zone
+  --label <label>                   Events: only events with this label, e.g. person
+  --since <time>                    Events: only events ending after this time
+  --until <time>                    Events: only events starting before this time
+  --min-score <score>               Events: only events with a detection scoring at least this
+  --limit <n>                       Events: maximum number of events listed [default: 50]
+  --rebuild                         Events: rebuild the index from the files in captures first
+  -i --identity <identity-file>     Decrypt: age identity file with the private key
+  --key <public-key-file>           Verify: the device's device.pub, to check the bundle was signed by it
 ";
 
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
-    pub cameras: Vec<Camera>
+    pub cameras: Vec<Camera>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default = "default_storage_root")]
+    pub storage_root: String,
+    #[serde(default)]
+    pub paths: PathTemplates,
+    pub encryption: Option<Encryption>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    pub mqtt: Option<Mqtt>,
+    pub email: Option<Email>,
 }
 
 impl Config
@@ -31,7 +70,13 @@ impl Config
     pub fn load(filename: &str) -> Result<Config, Box<dyn std::error::Error>>
     {
         let contents = fs::read_to_string(Path::new(filename))?;
-        let config = serde_json::from_str(&contents)?;
+        let config: Config = serde_json::from_str(&contents)?;
+        config.hooks.check()?;
+        config.storage.check()?;
+        for camera in &config.cameras
+        {
+            camera.check().map_err(|e| format!("{}: {}", &camera.name, e))?;
+        }
         Ok(config)
     }
 }
@@ -43,5 +88,29 @@ pub struct CliConfig {
     pub flag_timelapse: bool,
     pub flag_polygon: Option<String>,
     pub flag_config: Option<String>,
+    pub cmd_export: bool,
+    pub arg_camera: String,
+    pub arg_start: String,
+    pub arg_end: String,
+    pub flag_output: Option<String>,
+    pub flag_from: Option<String>,
+    pub flag_overlay: bool,
+    pub cmd_events: bool,
+    pub flag_camera: Option<String>,
+    pub flag_zone: Option<String>,
+    pub flag_label: Option<String>,
+    pub flag_since: Option<String>,
+    pub flag_until: Option<String>,
+    pub flag_min_score: Option<f32>,
+    pub flag_limit: u32,
+    pub flag_rebuild: bool,
+    pub cmd_decrypt: bool,
+    pub arg_file: Vec<String>,
+    pub flag_identity: Option<String>,
+    pub cmd_export_evidence: bool,
+    pub arg_event_id: Vec<String>,
+    pub cmd_verify: bool,
+    pub arg_bundle: String,
+    pub flag_key: Option<String>,
 }
 
@@ -0,0 +1,215 @@
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+use opencv::core::Size;
+
+use crate::index;
+use crate::metadata::{SegmentMetadata, sidecar_filename};
+use crate::paths;
+use crate::retention::Category;
+use crate::overlay::{original_filename, Overlay};
+use crate::recorder::{close_writer, open_writer, PacedWriter, TimedFrame, write_frame};
+use crate::storage::write_error;
+
+const LOG_HOURS: i64 = 24;  // closed segments are forgotten after this long
+
+/// Continuous recording in fixed length segments, for cameras that need footage around the clock
+#[derive(Debug, Deserialize, Clone)]
+pub struct Continuous {
+    #[serde(default = "default_segment_seconds")]
+    pub segment_seconds: u64,
+}
+
+fn default_segment_seconds() -> u64 { 300 }
+
+/// The part of a segment covering an event, offsets are seconds from the start of the segment
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SegmentRange {
+    pub video: String,
+    pub start_offset: f64,
+    pub end_offset: f64,
+}
+
+struct Segment {
+    video: String,
+    start: DateTime<Local>,
+    end: Option<DateTime<Local>>,   // None while it's being written
+}
+
+/// Recent segments of a camera, shared with the person event writers
+#[derive(Clone)]
+pub struct SegmentLog {
+    segments: Arc<Mutex<Vec<Segment>>>,
+}
+
+impl SegmentLog
+{
+    fn new() -> SegmentLog
+    {
+        SegmentLog { segments: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    fn opened(&self, video: &str, start: DateTime<Local>)
+    {
+        let mut segments = self.segments.lock().unwrap();
+        let cutoff = start - Duration::hours(LOG_HOURS);
+        segments.retain(|s| s.end.is_none_or(|end| end > cutoff));
+        segments.push(Segment { video: video.to_string(), start, end: None });
+    }
+
+    fn closed(&self, video: &str, end: DateTime<Local>)
+    {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(s) = segments.iter_mut().find(|s| s.video == video) { s.end = Some(end); }
+    }
+
+    /// The segments covering a span of time, in order
+    pub fn ranges(&self, start: DateTime<Local>, end: DateTime<Local>) -> Vec<SegmentRange>
+    {
+        let segments = self.segments.lock().unwrap();
+        segments.iter()
+            .filter(|s| s.start <= end && s.end.is_none_or(|e| e >= start))
+            .map(|s| {
+                let from = if start > s.start { start } else { s.start };
+                let to = match s.end {
+                    Some(e) if e < end => e,
+                    _ => end,
+                };
+                SegmentRange {
+                    video: s.video.clone(),
+                    start_offset: seconds_between(s.start, from),
+                    end_offset: seconds_between(s.start, to),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Feeds a camera's frames to the segment writer thread
+pub struct ContinuousRecorder {
+    tx: Sender<TimedFrame>,
+    pub log: SegmentLog,
+}
+
+impl ContinuousRecorder
+{
+    pub fn start(camera: &str, continuous: &Continuous, overlay: &Option<Overlay>, fps: f64, size: Size) -> ContinuousRecorder
+    {
+        let (tx, rx) = mpsc::channel::<TimedFrame>();
+        let log = SegmentLog::new();
+        let mut writer = SegmentWriter {
+            camera: camera.to_string(),
+            segment_seconds: continuous.segment_seconds.max(1) as i64,
+            overlay: overlay.clone(),
+            fps,
+            size,
+            log: log.clone(),
+            current: None,
+        };
+
+        // Like the person recordings, writing in the capture thread would stall the input stream
+        thread::spawn(move || {
+            for f in rx { writer.write(f); }
+            writer.close();
+        });
+
+        ContinuousRecorder { tx, log }
+    }
+
+    pub fn write(&self, f: TimedFrame)
+    {
+        if let Err(e) = self.tx.send(f) { error!("Continuous recording has stopped: {}", e); }
+    }
+}
+
+struct OpenSegment {
+    index: i64,
+    metadata: SegmentMetadata,
+    writer: Option<PacedWriter>,
+    original: Option<PacedWriter>,
+    last_time: DateTime<Local>,
+}
+
+struct SegmentWriter {
+    camera: String,
+    segment_seconds: i64,
+    overlay: Option<Overlay>,
+    fps: f64,
+    size: Size,
+    log: SegmentLog,
+    current: Option<OpenSegment>,
+}
+
+impl SegmentWriter
+{
+    fn write(&mut self, f: TimedFrame)
+    {
+        // Segments start on multiples of their length, e.g. every 5 minutes past the hour
+        let index = f.time.naive_local().timestamp() / self.segment_seconds;
+        if self.current.as_ref().is_none_or(|s| s.index != index)
+        {
+            // The next segment is opened before the last is closed, so no frames are lost in between
+            let next = self.open(index, &f.time);
+            if let Some(last) = self.current.replace(next) { self.finish(last); }
+        }
+
+        let overlay = &self.overlay;
+        let camera = &self.camera;
+        if let Some(s) = &mut self.current
+        {
+            let (frame, original) = match overlay {
+                Some(overlay) => (overlay.render(camera, &f), Some(f.frame)),
+                None => (f.frame, None),
+            };
+            write_frame(&mut s.writer, frame, f.pts, &s.metadata.video);
+            if let Some(original) = original { write_frame(&mut s.original, original, f.pts, &original_filename(&s.metadata.video)); }
+            s.last_time = f.time;
+        }
+    }
+
+    fn open(&self, index: i64, time: &DateTime<Local>) -> OpenSegment
+    {
+        let video = paths::file(Category::Continuous, &self.camera, time, &format!("{}{}.mp4", &self.camera, time.format("%Y%m%d-%H%M%S")));
+        let interval = 1000.0 / self.fps;
+        let writer = open_writer(&video, self.fps, self.size, interval);
+        let original = match &self.overlay {
+            Some(overlay) if overlay.keep_original => open_writer(&original_filename(&video), self.fps, self.size, interval),
+            _ => None
+        };
+        self.log.opened(&video, *time);
+
+        OpenSegment {
+            index,
+            metadata: SegmentMetadata::new(&self.camera, &video, time, self.fps, interval / 1000.0),
+            writer,
+            original,
+            last_time: *time,
+        }
+    }
+
+    fn finish(&self, mut s: OpenSegment)
+    {
+        if let Some(w) = &s.writer { s.metadata.gaps = w.gaps().to_vec(); }
+        close_writer(&mut s.writer, &s.metadata.video);
+        close_writer(&mut s.original, &original_filename(&s.metadata.video));
+        s.metadata.end = s.last_time.to_rfc3339();
+        self.log.closed(&s.metadata.video, s.last_time);
+
+        let sidecar = sidecar_filename(&s.metadata.video);
+        if let Err(e) = s.metadata.write(&sidecar) { write_error(&sidecar, &e); }
+        index::record_segment("continuous", &s.metadata);
+    }
+
+    fn close(&mut self)
+    {
+        if let Some(last) = self.current.take() { self.finish(last); }
+    }
+}
+
+fn seconds_between(from: DateTime<Local>, to: DateTime<Local>) -> f64
+{
+    ((to - from).num_milliseconds() as f64 / 1000.0).max(0.0)
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+
+use crate::encryption;
+use crate::events::{Event, EventSink};
+use crate::throttle::Throttle;
+
+/// Emails events through an SMTP server, with the first or best image inline
+#[derive(Debug, Deserialize, Clone)]
+pub struct Email {
+    pub host: String,
+    pub port: Option<u16>,              // the default for the security if not set
+    #[serde(default)]
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub cameras: HashMap<String, Vec<String>>,  // recipients for particular cameras, instead of `to`
+    #[serde(default = "default_events")]
+    pub events: Vec<String>,            // event names to email
+    #[serde(default)]
+    pub attach_gif: bool,               // attach the preview gif to event_ended emails
+    #[serde(default)]
+    pub digest_secs: u64,               // events within this of the first are sent together, 0 sends each as it happens
+    #[serde(default = "default_backoff_secs")]
+    pub backoff_secs: u64,              // wait after a failure, doubling each time up to max_backoff_secs
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,              // the oldest events waiting to be retried are dropped beyond this
+    #[serde(default)]
+    pub throttle: Throttle,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    #[default]
+    Starttls,
+    Tls,
+    None,       // plain text, only for a server on the same host or network
+}
+
+fn default_events() -> Vec<String> { vec!["event_ended".to_string()] }
+fn default_backoff_secs() -> u64 { 5 }
+fn default_max_backoff_secs() -> u64 { 600 }
+fn default_max_queued() -> usize { 1000 }
+
+pub struct EmailSink {
+    config: Email,
+    queue: Sender<Event>,
+}
+
+impl EmailSink
+{
+    pub fn new(config: &Email) -> Result<EmailSink, Box<dyn Error>>
+    {
+        let mut builder = match config.security {
+            Security::Starttls => SmtpTransport::starttls_relay(&config.host)?,
+            Security::Tls => SmtpTransport::relay(&config.host)?,
+            Security::None => SmtpTransport::builder_dangerous(&config.host),
+        };
+        if let Some(port) = config.port { builder = builder.port(port); }
+        if let (Some(username), Some(password)) = (&config.username, &config.password)
+        {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let transport = builder.timeout(Some(Duration::from_secs(30))).build();
+
+        // Check the addresses now rather than when there's something to send
+        config.from.parse::<Mailbox>()?;
+        for to in config.to.iter().chain(config.cameras.values().flatten()) { to.parse::<Mailbox>()?; }
+
+        let (queue, rx) = mpsc::channel();
+        let c = config.clone();
+        thread::spawn(move || deliver(&c, &transport, rx));
+        Ok(EmailSink { config: config.clone(), queue })
+    }
+}
+
+impl EventSink for EmailSink
+{
+    fn name(&self) -> &str { "email" }
+
+    fn handle(&mut self, event: &Event)
+    {
+        let event = match event {
+            // Only the events asked for from a quiet hours digest
+            Event::Digest { start, end, events } => {
+                let events: Vec<Event> = events.iter().filter(|e| e.is_one_of(&self.config.events)).cloned().collect();
+                if events.is_empty() { return; }
+                Event::Digest { start: start.clone(), end: end.clone(), events }
+            }
+            _ if event.is_one_of(&self.config.events) => event.clone(),
+            _ => { return; }
+        };
+        if let Err(e) = self.queue.send(event) { error!("Email sender has stopped: {}", e); }
+    }
+}
+
+// Send events as they come, or gather them into a digest sent digest_secs after the first.
+// Events that couldn't be sent are tried again after a backoff, along with any that arrive meanwhile.
+fn deliver(config: &Email, transport: &SmtpTransport, rx: Receiver<Event>)
+{
+    let mut batch = Vec::new();
+    let mut due: Option<Instant> = None;
+    let mut pending = Vec::new();
+    let mut retry: Option<Instant> = None;
+    // A zero backoff would retry a failing server as fast as it can
+    let first_backoff = config.backoff_secs.max(1);
+    let mut backoff = first_backoff;
+    loop
+    {
+        let wake = [due, retry.filter(|_| !pending.is_empty())].into_iter().flatten().min();
+        let received = match wake {
+            Some(wake) => rx.recv_timeout(wake.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(event) if config.digest_secs == 0 => { pending.push(event); }
+            Ok(event) => {
+                if batch.is_empty() { due = Some(Instant::now() + Duration::from_secs(config.digest_secs)); }
+                batch.push(event);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                pending.append(&mut batch);
+                if !pending.is_empty() { send(config, transport, &pending); }
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        if due.is_some_and(|due| due <= now)
+        {
+            pending.append(&mut batch);
+            due = None;
+        }
+        if pending.is_empty() || retry.is_some_and(|retry| retry > now) { continue; }
+
+        pending = send(config, transport, &pending);
+        if pending.is_empty()
+        {
+            retry = None;
+            backoff = first_backoff;
+            continue;
+        }
+        if pending.len() > config.max_queued
+        {
+            let dropped = pending.len() - config.max_queued;
+            warn!("Dropping the oldest {} events waiting to be emailed", dropped);
+            pending.drain(..dropped);
+        }
+        warn!("Will try emailing {} events again in {}s", pending.len(), backoff);
+        retry = Some(now + Duration::from_secs(backoff));
+        backoff = backoff.saturating_mul(2).min(config.max_backoff_secs.max(first_backoff));
+    }
+}
+
+// Email events to their recipients, one message for each set of recipients. Returns the events to try again.
+fn send(config: &Email, transport: &SmtpTransport, events: &[Event]) -> Vec<Event>
+{
+    let mut groups: Vec<(&Vec<String>, Vec<&Event>)> = Vec::new();
+    let events = events.iter().flat_map(|e| match e {
+        Event::Digest { events, .. } => events.iter().collect(),
+        _ => vec![e],
+    });
+    for event in events
+    {
+        let to = event.camera().and_then(|c| config.cameras.get(c)).unwrap_or(&config.to);
+        match groups.iter_mut().find(|(t, _)| *t == to) {
+            Some((_, group)) => group.push(event),
+            None => groups.push((to, vec![event])),
+        }
+    }
+
+    let mut unsent = Vec::new();
+    for (to, events) in groups
+    {
+        if to.is_empty() { continue; }
+        // A message that can't be built, or that the server refuses outright, won't go however many times it's sent
+        let message = match message(config, to, &events) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Can't email {} events to {}: {}", events.len(), to.join(", "), e);
+                continue;
+            }
+        };
+        match transport.send(&message) {
+            Ok(_) => { info!("Emailed {} events to {}", events.len(), to.join(", ")); }
+            Err(e) if e.is_permanent() => { error!("Email of {} events to {} rejected, dropping it: {}", events.len(), to.join(", "), e); }
+            Err(e) => {
+                warn!("Can't email {} events to {}: {}", events.len(), to.join(", "), e);
+                unsent.extend(events.into_iter().cloned());
+            }
+        }
+    }
+    unsent
+}
+
+fn message(config: &Email, to: &[String], events: &[&Event]) -> Result<Message, Box<dyn Error>>
+{
+    let subject = match events {
+        [event] => describe(event).0,
+        _ => format!("{} security camera events", events.len()),
+    };
+
+    let mut text = String::new();
+    let mut html = String::new();
+    let mut images = Vec::new();
+    let mut gifs = Vec::new();
+    for (i, event) in events.iter().enumerate()
+    {
+        let (title, detail) = describe(event);
+        text += &format!("{}\n{}\n\n", title, detail);
+        html += &format!("<h3>{}</h3><p>{}</p>", escape(&title), escape(&detail).replace('\n', "<br>"));
+
+        if let Some(data) = image(event).and_then(read_image)
+        {
+            let cid = format!("image{}", i);
+            html += &format!("<p><img src=\"cid:{}\" style=\"max-width: 100%\"></p>", cid);
+            images.push((cid, data));
+        }
+        if let Event::Ended { preview_gif, .. } = event
+        {
+            if config.attach_gif && !preview_gif.is_empty()
+            {
+                if let Some(data) = read_image(preview_gif)
+                {
+                    gifs.push((Path::new(preview_gif).file_name().unwrap_or_default().to_string_lossy().to_string(), data));
+                }
+            }
+        }
+    }
+
+    let mut related = MultiPart::related().singlepart(SinglePart::html(html));
+    for (cid, data) in images { related = related.singlepart(Attachment::new_inline(cid).body(data, ContentType::parse("image/jpeg")?)); }
+    let mut mixed = MultiPart::mixed().multipart(MultiPart::alternative().singlepart(SinglePart::plain(text)).multipart(related));
+    for (name, data) in gifs { mixed = mixed.singlepart(Attachment::new(name).body(data, ContentType::parse("image/gif")?)); }
+
+    let mut builder = Message::builder().from(config.from.parse()?).subject(subject);
+    for address in to { builder = builder.to(address.parse()?); }
+    Ok(builder.multipart(mixed)?)
+}
+
+// A subject line and a description of an event
+fn describe(event: &Event) -> (String, String)
+{
+    match event {
+        Event::Started { camera, time, zones, label, score, .. } => {
+            (format!("{}: {} detected", camera, label), format!("At {} in {}, score {:.2}", time, zones.join(", "), score))
+        }
+        Event::BestImageUpdated { camera, event_id, time, score, .. } => {
+            (format!("{}: better image of {}", camera, event_id), format!("At {}, score {:.2}", time, score))
+        }
+        Event::Ended { camera, event_id, start, end, video, zones, labels, max_score, .. } => {
+            (format!("{}: {} recorded", camera, labels.join(", ")),
+             format!("Event {} from {} to {} in {}, best score {:.2}\nVideo: {}", event_id, start, end, zones.join(", "), max_score, video))
+        }
+        Event::TimelapseRollover { camera, video } => (format!("{}: timelapse written", camera), video.clone()),
+        Event::CameraOnline { camera } => (format!("{}: online", camera), String::new()),
+        Event::CameraOffline { camera, reason } => (format!("{}: offline", camera), reason.clone()),
+        Event::Storage { kind, detail } => (format!("Storage: {}", kind.name()), detail.clone()),
+        Event::Digest { start, end, events } => (format!("{} events in the quiet hours", events.len()), format!("From {} to {}", start, end)),
+    }
+}
+
+fn image(event: &Event) -> Option<&str>
+{
+    match event {
+        Event::Started { first_image, .. } => Some(first_image),
+        Event::Ended { best_image, .. } => Some(best_image),
+        _ => None,
+    }
+}
+
+// An image to attach, encrypted ones can't be shown
+fn read_image(filename: &str) -> Option<Vec<u8>>
+{
+    if filename.is_empty() || encryption::is_encrypted(filename) { return None; }
+    match fs::read(filename) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            warn!("Can't attach {} to the email: {}", filename, e);
+            None
+        }
+    }
+}
+
+fn escape(text: &str) -> String
+{
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // An SMTP server taking a connection for each reply, which refuses the sender with it or, if None, passes on the message
+    fn server(replies: Vec<Option<&'static str>>) -> (u16, Receiver<String>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for reply in replies
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+                let mut data: Option<String> = None;
+                loop
+                {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 { break; }
+                    if data.is_some()
+                    {
+                        if line == ".\r\n"
+                        {
+                            let _ = tx.send(data.take().unwrap_or_default());
+                            stream.write_all(b"250 OK\r\n").unwrap();
+                        }
+                        else if let Some(message) = data.as_mut() { message.push_str(&line); }
+                        continue;
+                    }
+                    let command = line.to_uppercase();
+                    let response = match command.get(..4).unwrap_or_default() {
+                        "EHLO" | "HELO" => "250 localhost",
+                        "MAIL" => reply.unwrap_or("250 OK"),
+                        "DATA" => { data = Some(String::new()); "354 Go ahead" }
+                        "QUIT" => "221 Bye",
+                        _ => "250 OK",
+                    };
+                    if stream.write_all(format!("{}\r\n", response).as_bytes()).is_err() || command.starts_with("QUIT") { break; }
+                }
+            }
+        });
+        (port, rx)
+    }
+
+    fn sink(port: u16) -> EmailSink
+    {
+        let config: Email = serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1", "port": port, "security": "none", "backoff_secs": 1,
+            "from": "camera@example.com", "to": ["me@example.com"], "events": ["camera_offline"],
+        })).unwrap();
+        EmailSink::new(&config).unwrap()
+    }
+
+    fn offline(camera: &str) -> Event
+    {
+        Event::CameraOffline { camera: camera.to_string(), reason: "stream ended".to_string() }
+    }
+
+    #[test]
+    fn emails_the_events_asked_for()
+    {
+        let (port, messages) = server(vec![None]);
+        let mut sink = sink(port);
+        sink.handle(&Event::CameraOnline { camera: "Door".to_string() });
+        sink.handle(&offline("Door"));
+
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(message.contains("Subject: Door: offline\r\n"));
+        assert!(message.contains("To: me@example.com\r\n"));
+        assert!(message.contains("From: camera@example.com\r\n"));
+    }
+
+    #[test]
+    fn a_failed_email_is_sent_again()
+    {
+        let (port, messages) = server(vec![Some("451 Try again later"), None]);
+        let mut sink = sink(port);
+        sink.handle(&offline("Door"));
+
+        assert!(messages.recv_timeout(Duration::from_millis(500)).is_err());
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(message.contains("Subject: Door: offline\r\n"));
+    }
+
+    #[test]
+    fn a_refused_email_is_dropped()
+    {
+        let (port, messages) = server(vec![Some("550 No such sender"), None]);
+        let mut sink = sink(port);
+        sink.handle(&offline("Door"));
+        thread::sleep(Duration::from_millis(500));
+        sink.handle(&offline("Garage"));
+
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(message.contains("Subject: Garage: offline\r\n"));
+        assert!(!message.contains("Door"));
+    }
+}
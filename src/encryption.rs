@@ -0,0 +1,113 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use serde::Deserialize;
+
+use crate::storage::{finalise, part_filename};
+
+pub const EXTENSION: &str = ".age";
+
+/// Encrypt recordings and photos as they're finished with age public keys, e.g. made with `age-keygen`.
+/// Only the public keys are needed to record, so footage can't be read back from the device.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Encryption {
+    pub recipients: Vec<String>,    // age1...
+}
+
+static RECIPIENTS: OnceLock<Vec<age::x25519::Recipient>> = OnceLock::new();
+
+/// Check the keys and turn encryption on, before any cameras start
+pub fn init(encryption: &Encryption) -> Result<(), Box<dyn Error>>
+{
+    let mut recipients = Vec::new();
+    for r in &encryption.recipients
+    {
+        recipients.push(age::x25519::Recipient::from_str(r).map_err(|e| format!("Bad encryption recipient {}: {}", r, e))?);
+    }
+    if recipients.is_empty() { return Err("Encryption needs at least one recipient".into()); }
+
+    info!("Recordings and photos will be encrypted for {} recipients.", recipients.len());
+    if RECIPIENTS.set(recipients).is_err() { warn!("Encryption already set."); }
+    Ok(())
+}
+
+pub fn enabled() -> bool
+{
+    RECIPIENTS.get().is_some()
+}
+
+/// Name a capture is stored under, recordings and photos get `.age` added when encryption is on.
+/// Sidecars stay readable as the index, export and retention need them.
+pub fn stored_name(filename: &str) -> String
+{
+    if enabled() && !filename.ends_with(".json") && !is_encrypted(filename) { format!("{}{}", filename, EXTENSION) } else { filename.to_string() }
+}
+
+/// The name without `.age`, e.g. to work out the names of related files
+pub fn plain_name(filename: &str) -> &str
+{
+    filename.strip_suffix(EXTENSION).unwrap_or(filename)
+}
+
+pub fn is_encrypted(filename: &str) -> bool
+{
+    filename.ends_with(EXTENSION)
+}
+
+/// Encrypt a file for the recipients
+pub fn encrypt_file(from: &str, to: &str) -> io::Result<()>
+{
+    let recipients = RECIPIENTS.get().ok_or_else(|| io::Error::other("encryption isn't set up"))?;
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+        .map_err(io::Error::other)?;
+
+    let mut reader = BufReader::new(File::open(from)?);
+    let mut writer = encryptor.wrap_output(BufWriter::new(File::create(to)?))?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Decrypt files with the private keys in an identity file, each is written alongside without the `.age`.
+/// Returns the number of files decrypted.
+pub fn decrypt(files: &[String], identity_file: &str) -> Result<usize, Box<dyn Error>>
+{
+    let identities = age::IdentityFile::from_file(identity_file.to_string())?.into_identities()?;
+
+    let mut decrypted = 0;
+    for file in files
+    {
+        if !is_encrypted(file)
+        {
+            warn!("Skipping {}, it isn't encrypted.", file);
+            continue;
+        }
+        let output = plain_name(file);
+        match decrypt_file(file, output, &identities) {
+            Ok(_) => {
+                info!("Decrypted {} to {}", file, output);
+                decrypted += 1;
+            }
+            Err(e) => { error!("Can't decrypt {}: {}", file, e); }
+        }
+    }
+    Ok(decrypted)
+}
+
+fn decrypt_file(from: &str, to: &str, identities: &[Box<dyn age::Identity>]) -> Result<(), Box<dyn Error>>
+{
+    let decryptor = age::Decryptor::new(BufReader::new(File::open(from)?))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))?;
+    {
+        let mut writer = BufWriter::new(File::create(part_filename(to))?);
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+    }
+    if let Err(e) = finalise(to)
+    {
+        let _ = fs::remove_file(part_filename(to));
+        return Err(e.into());
+    }
+    Ok(())
+}
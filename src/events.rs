@@ -0,0 +1,176 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// The names of the types of event, as in their JSON
+pub const EVENT_NAMES: [&str; 8] = [
+    "event_started", "best_image_updated", "event_ended", "timelapse_rollover", "camera_online", "camera_offline", "storage", "digest",
+];
+
+/// Something that happened, published on the event bus for the sinks to pass on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A person in the zones has started a recording
+    #[serde(rename = "event_started")]
+    Started {
+        camera: String,
+        event_id: String,
+        time: String,
+        first_image: String,
+        zones: Vec<String>,
+        label: String,
+        score: f32,
+    },
+    /// A better image of the person has been seen, it's written when the clip ends
+    BestImageUpdated {
+        camera: String,
+        event_id: String,
+        part: u32,
+        time: String,
+        score: f32,
+    },
+    /// A clip of an event has been written. `best_image` is the first image if there wasn't a better one,
+    /// the other names are empty when they weren't written.
+    #[serde(rename = "event_ended")]
+    Ended {
+        camera: String,
+        event_id: String,
+        part: u32,
+        start: String,
+        end: String,
+        video: String,
+        best_image: String,
+        preview_gif: String,
+        contact_sheet: String,
+        sidecar: String,
+        zones: Vec<String>,
+        labels: Vec<String>,
+        max_score: f32,
+    },
+    TimelapseRollover {
+        camera: String,
+        video: String,      // the file just closed
+    },
+    CameraOnline {
+        camera: String,
+    },
+    CameraOffline {
+        camera: String,
+        reason: String,
+    },
+    Storage {
+        kind: StorageEvent,
+        detail: String,
+    },
+    /// The events held back during quiet hours, passed on together when they're over
+    Digest {
+        start: String,
+        end: String,
+        events: Vec<Event>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEvent {
+    StorageLow,
+    WriteError,
+    PartRecovered,
+    PartQuarantined,
+    ManifestBroken,
+}
+
+impl StorageEvent
+{
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            StorageEvent::StorageLow => "storage_low",
+            StorageEvent::WriteError => "write_error",
+            StorageEvent::PartRecovered => "part_recovered",
+            StorageEvent::PartQuarantined => "part_quarantined",
+            StorageEvent::ManifestBroken => "manifest_broken",
+        }
+    }
+}
+
+impl Event
+{
+    /// Name of the type of event, as in its JSON
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            Event::Started { .. } => "event_started",
+            Event::BestImageUpdated { .. } => "best_image_updated",
+            Event::Ended { .. } => "event_ended",
+            Event::TimelapseRollover { .. } => "timelapse_rollover",
+            Event::CameraOnline { .. } => "camera_online",
+            Event::CameraOffline { .. } => "camera_offline",
+            Event::Storage { .. } => "storage",
+            Event::Digest { .. } => "digest",
+        }
+    }
+
+    /// The camera it happened to, storage events and digests aren't for any one camera
+    pub fn camera(&self) -> Option<&str>
+    {
+        match self {
+            Event::Started { camera, .. } | Event::BestImageUpdated { camera, .. } | Event::Ended { camera, .. }
+            | Event::TimelapseRollover { camera, .. } | Event::CameraOnline { camera } | Event::CameraOffline { camera, .. } => Some(camera),
+            Event::Storage { .. } | Event::Digest { .. } => None,
+        }
+    }
+
+    /// Whether it's one of these types of event, a digest is if any of its events are
+    pub fn is_one_of(&self, names: &[String]) -> bool
+    {
+        match self {
+            Event::Digest { events, .. } => events.iter().any(|e| e.is_one_of(names)),
+            _ => names.iter().any(|n| n == self.name()),
+        }
+    }
+}
+
+/// Somewhere events are passed on to, e.g. scripts, a webhook or MQTT
+pub trait EventSink: Send
+{
+    fn name(&self) -> &str;
+    fn handle(&mut self, event: &Event);
+
+    /// Called when there have been no events for a second, for sinks that pass things on later
+    fn tick(&mut self) {}
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+
+/// Add a sink to the bus. Each sink has its own thread so a slow one doesn't hold up the cameras or the other sinks,
+/// it's given the events in the order they were published.
+pub fn subscribe(mut sink: Box<dyn EventSink>)
+{
+    let (tx, rx) = mpsc::channel::<Event>();
+    info!("Events will be sent to {}.", sink.name());
+    thread::spawn(move || {
+        loop
+        {
+            match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => sink.handle(&event),
+                Err(RecvTimeoutError::Timeout) => sink.tick(),
+                Err(RecvTimeoutError::Disconnected) => { return; }
+            }
+        }
+    });
+    SUBSCRIBERS.lock().unwrap().push(tx);
+}
+
+/// Pass an event to all the sinks
+pub fn publish(event: Event)
+{
+    debug!("Event: {:?}", &event);
+    for tx in SUBSCRIBERS.lock().unwrap().iter()
+    {
+        if let Err(e) = tx.send(event.clone()) { error!("Event sink has stopped: {}", e); }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::{self, check_chain, hash_file, ManifestEntry};
+use crate::paths;
+use crate::retention::{self, Category};
+use crate::storage::is_part;
+
+const BUNDLE: &str = "bundle.json";
+const SIGNATURE: &str = "bundle.sig";
+const MANIFEST: &str = "manifest.jsonl";
+const FILES: &str = "files";
+
+/// What's in an evidence bundle, signed by the device key
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    created: String,
+    device_key: String,         // Ed25519 public key, hex
+    events: Vec<String>,
+    files: Vec<BundleFile>,
+    manifest_sha256: String,
+    manifest_head: String,      // hash of the last manifest entry
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleFile {
+    name: String,               // in the files directory
+    path: String,               // where it was recorded
+    sha256: String,
+    seq: Option<u64>,           // its manifest entry, None if it was never recorded
+}
+
+/// Copy events' files into a bundle directory with the manifest, the file list and a signature from the device key.
+/// Returns the name of the bundle directory.
+pub fn export_evidence(event_ids: &[String], output: Option<&str>) -> Result<String, Box<dyn Error>>
+{
+    if event_ids.is_empty() { return Err("No events given".into()); }
+    let key = manifest::device_key()?;
+    let entries = manifest::load(&manifest::manifest_filename())?;
+    if let Err(e) = check_chain(&entries) { warn!("The manifest is broken, the bundle won't verify: {}", e); }
+
+    // The latest entry for each file, in case a name was reused
+    let recorded: HashMap<&str, &ManifestEntry> = entries.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    let files = event_files(event_ids);
+    if files.is_empty() { return Err(format!("No files found for {}", event_ids.join(", ")).into()); }
+
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => format!("evidence-{}", &event_ids[0]),
+    };
+    if Path::new(&output).exists() { return Err(format!("{} already exists", &output).into()); }
+    fs::create_dir_all(Path::new(&output).join(FILES))?;
+
+    let mut bundle_files = Vec::new();
+    for path in &files
+    {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let path = path.to_string_lossy().to_string();
+        let (sha256, _) = hash_file(&path)?;
+        let seq = match recorded.get(path.as_str()) {
+            Some(entry) if entry.sha256 == sha256 => Some(entry.seq),
+            Some(_) => { warn!("{} has changed since it was recorded in the manifest.", &path); None }
+            None => { warn!("{} isn't in the manifest.", &path); None }
+        };
+        fs::copy(&path, Path::new(&output).join(FILES).join(&name))?;
+        bundle_files.push(BundleFile { name, path, sha256, seq });
+    }
+
+    // The manifest as it was read, as later entries may be added while this runs
+    let mut lines = String::new();
+    for entry in &entries { lines += &format!("{}\n", serde_json::to_string(entry)?); }
+    let manifest_file = Path::new(&output).join(MANIFEST);
+    fs::write(&manifest_file, lines)?;
+
+    let bundle = Bundle {
+        created: Local::now().to_rfc3339(),
+        device_key: hex::encode(key.verifying_key().to_bytes()),
+        events: event_ids.to_vec(),
+        files: bundle_files,
+        manifest_sha256: hash_file(&manifest_file.to_string_lossy())?.0,
+        manifest_head: entries.last().map(|e| e.hash.clone()).unwrap_or_default(),
+    };
+    let json = serde_json::to_string_pretty(&bundle)?;
+    fs::write(Path::new(&output).join(BUNDLE), &json)?;
+    fs::write(Path::new(&output).join(SIGNATURE), hex::encode(key.sign(json.as_bytes()).to_bytes()))?;
+
+    info!("Bundled {} files of {} events into {}", bundle.files.len(), event_ids.len(), &output);
+    Ok(output)
+}
+
+// Every file of the events, found the same way retention finds the files of events marked keep
+fn event_files(event_ids: &[String]) -> Vec<PathBuf>
+{
+    let prefixes: Vec<String> = event_ids.iter().flat_map(|id| retention::event_prefixes(id)).collect();
+    let mut files = Vec::new();
+    for category in Category::all()
+    {
+        for path in paths::files(category)
+        {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if is_part(&path) || name.ends_with(".keep") { continue; }
+            if prefixes.iter().any(|p| name.starts_with(p.as_str())) && !files.contains(&path) { files.push(path); }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Check a bundle without the device: its signature, the manifest chain and every file against it.
+/// `key` is the device's public key file (`device.pub`), to check the bundle was signed by that device.
+/// Returns true if everything checks out, the problems are logged.
+pub fn verify(bundle_dir: &str, key: Option<&str>) -> Result<bool, Box<dyn Error>>
+{
+    let dir = Path::new(bundle_dir);
+    let json = fs::read_to_string(dir.join(BUNDLE))?;
+    let bundle: Bundle = serde_json::from_str(&json)?;
+    let mut ok = true;
+
+    // Signature
+    let device_key: [u8; 32] = hex::decode(&bundle.device_key)?.try_into().map_err(|_| "Bad device key in the bundle")?;
+    let device_key = VerifyingKey::from_bytes(&device_key)?;
+    let signature: [u8; 64] = hex::decode(fs::read_to_string(dir.join(SIGNATURE))?.trim())?.try_into().map_err(|_| "Bad signature")?;
+    match device_key.verify_strict(json.as_bytes(), &Signature::from_bytes(&signature)) {
+        Ok(_) => info!("Signature: OK"),
+        Err(e) => { error!("Signature: FAILED, {}", e); ok = false; }
+    }
+    if let Some(key) = key
+    {
+        match fs::read_to_string(key)?.trim() == bundle.device_key {
+            true => info!("Device key: OK, matches {}", key),
+            false => { error!("Device key: FAILED, the bundle wasn't signed by {}", key); ok = false; }
+        }
+    }
+
+    // Manifest
+    let manifest_file = dir.join(MANIFEST).to_string_lossy().to_string();
+    let entries = manifest::load(&manifest_file)?;
+    if hash_file(&manifest_file)?.0 != bundle.manifest_sha256
+    {
+        error!("Manifest: FAILED, it isn't the manifest that was signed");
+        ok = false;
+    }
+    match check_chain(&entries) {
+        Ok(_) if entries.last().map(|e| e.hash.as_str()).unwrap_or_default() == bundle.manifest_head => {
+            info!("Manifest: OK, {} entries", entries.len());
+        }
+        Ok(_) => { error!("Manifest: FAILED, the last entry isn't the one signed"); ok = false; }
+        Err(e) => { error!("Manifest: FAILED, {}", e); ok = false; }
+    }
+
+    // Files
+    for f in &bundle.files
+    {
+        let sha256 = hash_file(&dir.join(FILES).join(&f.name).to_string_lossy()).map(|h| h.0).unwrap_or_default();
+        let entry = f.seq.and_then(|seq| entries.get(seq as usize));
+        match entry {
+            _ if sha256 != f.sha256 => { error!("{}: FAILED, it has been altered or is missing", &f.name); ok = false; }
+            Some(entry) if entry.path == f.path && entry.sha256 == f.sha256 => {
+                info!("{}: OK, recorded {}", &f.name, &entry.time);
+            }
+            Some(_) => { error!("{}: FAILED, it doesn't match its manifest entry", &f.name); ok = false; }
+            None => { error!("{}: FAILED, it isn't in the manifest", &f.name); ok = false; }
+        }
+    }
+    for extra in fs::read_dir(dir.join(FILES))?.flatten()
+    {
+        let name = extra.file_name().to_string_lossy().to_string();
+        if !bundle.files.iter().any(|f| f.name == name) { error!("{}: FAILED, it isn't listed in the bundle", &name); ok = false; }
+    }
+
+    Ok(ok)
+}
@@ -0,0 +1,198 @@
+use std::error::Error;
+use std::path::Path;
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone};
+
+use opencv::{prelude::*, videoio};
+use opencv::core::Size;
+use opencv::imgproc::{INTER_AREA, resize};
+use opencv::videoio::VideoCapture;
+
+use crate::encryption;
+use crate::metadata::SegmentMetadata;
+use crate::overlay::Overlay;
+use crate::paths;
+use crate::recorder::{PacedWriter, TimedFrame};
+use crate::retention::Category;
+use crate::storage::is_part;
+
+const TIME_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y%m%d-%H%M%S"];
+
+/// A recorded file along with the times of its frames
+struct Footage {
+    metadata: SegmentMetadata,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    frame_times: Vec<DateTime<Local>>,
+}
+
+impl Footage
+{
+    // Time of a frame of the video
+    fn frame_time(&self, index: usize) -> DateTime<Local>
+    {
+        match self.metadata.interval > 0.0 {
+            true => {
+                let skipped: f64 = self.metadata.gaps.iter().filter(|g| g.frame as usize <= index).map(|g| g.seconds).sum();
+                self.start + Duration::milliseconds(((index as f64 * self.metadata.interval + skipped) * 1000.0) as i64)
+            }
+            false => *self.frame_times.get(index).unwrap_or(&self.end),
+        }
+    }
+
+    // Index of the first frame at or after a time
+    fn frame_at(&self, time: DateTime<Local>) -> usize
+    {
+        if time <= self.start { return 0; }
+        match self.metadata.interval > 0.0 {
+            true => {
+                // Take out the gaps before the time, a time inside a gap gives the frame after it
+                let mut seconds = (time - self.start).num_milliseconds() as f64 / 1000.0;
+                for gap in &self.metadata.gaps
+                {
+                    let at = gap.frame as f64 * self.metadata.interval;
+                    if seconds <= at { break; }
+                    seconds = (seconds - gap.seconds).max(at);
+                }
+                (seconds / self.metadata.interval).ceil() as usize
+            }
+            false => self.frame_times.iter().position(|t| *t >= time).unwrap_or(self.frame_times.len()),
+        }
+    }
+}
+
+/// Cut a camera's footage between two times out of its continuous or timelapse recordings into one video.
+/// `from` picks `continuous` or `timelapse`, by default continuous recordings are used if there are any.
+/// Returns the filename of the video written.
+pub fn export(camera: &str, start: &str, end: &str, from: Option<&str>, output: Option<&str>, overlay: bool) -> Result<String, Box<dyn Error>>
+{
+    let start = parse_time(start)?;
+    let end = parse_time(end)?;
+    if end <= start { return Err("The end time must be after the start time".into()); }
+
+    let categories = match from {
+        Some("continuous") => vec![Category::Continuous],
+        Some("timelapse") => vec![Category::Timelapse],
+        Some(other) => { return Err(format!("Can't export from '{}', use continuous or timelapse", other).into()); }
+        None => vec![Category::Continuous, Category::Timelapse],
+    };
+    let mut footage = Vec::new();
+    for category in categories
+    {
+        footage = find_footage(camera, category, start, end);
+        if !footage.is_empty() { break; }
+    }
+    if footage.is_empty() { return Err(format!("No recordings of {} between {} and {}", camera, start, end).into()); }
+
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => format!("{}{}-export.mp4", camera, start.format("%Y%m%d-%H%M%S")),
+    };
+    let overlay = match overlay {
+        true => Some(Overlay { timestamp: true, camera_name: true, detections: false, keep_original: false }),
+        false => None,
+    };
+
+    let mut writer: Option<PacedWriter> = None;
+    let mut size = Size::default();
+    let mut frames = 0;
+    for f in &footage
+    {
+        info!("Exporting from {}", &f.metadata.video);
+        let mut cam = VideoCapture::from_file(&f.metadata.video, videoio::CAP_ANY)?;
+        if !cam.is_opened()? { warn!("Can't open {}, skipped.", &f.metadata.video); continue; }
+
+        let mut index = f.frame_at(start);
+        cam.set(videoio::CAP_PROP_POS_FRAMES, index as f64)?;
+        loop
+        {
+            let mut frame = Mat::default();
+            if !cam.read(&mut frame)? || frame.size()?.width == 0 { break; }
+            let time = f.frame_time(index);
+            if time > end { break; }
+            index += 1;
+
+            // All the footage goes into one video at the size and rate of the first file
+            if writer.is_none()
+            {
+                size = frame.size()?;
+                writer = Some(PacedWriter::create(&output, f.metadata.fps, size, 0.0)?);
+            }
+            if frame.size()? != size
+            {
+                let mut resized = Mat::default();
+                resize(&frame, &mut resized, size, 0.0, 0.0, INTER_AREA)?;
+                frame = resized;
+            }
+
+            let tf = TimedFrame { frame, pts: 0.0, time, detections: vec![] };
+            let out = match &overlay {
+                Some(overlay) => overlay.render(camera, &tf),
+                None => tf.frame,
+            };
+            if let Some(w) = &mut writer { w.write(out, 0.0)?; }
+            frames += 1;
+        }
+    }
+
+    match writer {
+        Some(mut w) => { w.release()?; }
+        None => { return Err(format!("No frames of {} between {} and {}", camera, start, end).into()); }
+    }
+    info!("Exported {} frames from {} files to {}", frames, footage.len(), &output);
+    Ok(output)
+}
+
+// The camera's files overlapping a span of time, oldest first. Only closed files have a sidecar and can be exported.
+fn find_footage(camera: &str, category: Category, start: DateTime<Local>, end: DateTime<Local>) -> Vec<Footage>
+{
+    let mut footage = Vec::new();
+    for path in paths::files(category)
+    {
+        if path.extension().is_none_or(|e| e != "json") || is_part(&path) { continue; }
+        let mut metadata = match SegmentMetadata::load(&path.to_string_lossy()) {
+            Ok(metadata) if metadata.camera == camera => metadata,
+            _ => continue,
+        };
+        let (s, e) = match (parse_rfc3339(&metadata.start), parse_rfc3339(&metadata.end)) {
+            (Some(s), Some(e)) => (s, e),
+            _ => continue,
+        };
+        if s > end || e < start { continue; }
+
+        // Encrypted footage can be exported once it's been decrypted alongside
+        if encryption::is_encrypted(&metadata.video)
+        {
+            let plain = encryption::plain_name(&metadata.video).to_string();
+            if !Path::new(&plain).exists()
+            {
+                warn!("{} is encrypted, decrypt it first to export from it.", &metadata.video);
+                continue;
+            }
+            metadata.video = plain;
+        }
+
+        let frame_times = metadata.frame_times.iter().filter_map(|t| parse_rfc3339(t)).collect();
+        footage.push(Footage { metadata, start: s, end: e, frame_times });
+    }
+
+    footage.sort_by_key(|f| f.start);
+    footage
+}
+
+fn parse_rfc3339(s: &str) -> Option<DateTime<Local>>
+{
+    DateTime::parse_from_rfc3339(s).ok().map(|t| t.with_timezone(&Local))
+}
+
+/// Local time given on the command line, e.g. `2023-05-19 02:14:00` or `20230519-021400`
+pub fn parse_time(s: &str) -> Result<DateTime<Local>, Box<dyn Error>>
+{
+    for format in TIME_FORMATS
+    {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format)
+        {
+            if let Some(t) = Local.from_local_datetime(&t).earliest() { return Ok(t); }
+        }
+    }
+    Err(format!("Can't read the time '{}', use e.g. '2023-05-19 02:14:00'", s).into())
+}
@@ -0,0 +1,529 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+use crate::camera::Camera;
+use crate::events::{Event, EventSink, EVENT_NAMES};
+use crate::throttle::Throttle;
+
+// The scripts and the arguments they're given
+const SCRIPTS: [(&str, &str); 5] = [
+    ("notify_start_person.sh", "<first-image-file>"),
+    ("notify_end_person.sh", "<best-image-file> <video-file> <event-id> <part> <preview-gif> <contact-sheet>"),
+    ("notify_timelapse_rollover.sh", "<video-file>"),
+    ("notify_camera.sh", "<camera> <online|offline> <reason>"),
+    ("notify_storage.sh", "<event> <detail>"),
+];
+
+/// Command lines to run for each type of event, keyed by the event's name e.g. `event_ended`.
+/// `{video}`, `{best_image}` etc. in the arguments are replaced with the event's fields.
+pub type HookCommands = HashMap<String, String>;
+
+/// How the hook scripts are run
+#[derive(Debug, Deserialize, Clone)]
+pub struct HooksConfig {
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,          // a script still running after this is killed
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,      // runs of each script at once, more events wait their turn
+    #[serde(default)]
+    pub retries: u32,               // times a failed or timed out script is run again
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    #[serde(default)]
+    pub commands: HookCommands,     // for cameras and zones without their own
+    #[serde(default)]
+    pub throttle: Throttle,
+}
+
+fn default_timeout_secs() -> u64 { 60 }
+fn default_max_concurrent() -> usize { 2 }
+fn default_retry_delay_secs() -> u64 { 10 }
+
+impl HooksConfig
+{
+    /// Check the settings when the config is loaded
+    pub fn check(&self) -> Result<(), String>
+    {
+        if self.max_concurrent == 0 { return Err("hooks max_concurrent should be at least 1".to_string()); }
+        Ok(())
+    }
+}
+
+impl Default for HooksConfig
+{
+    fn default() -> Self
+    {
+        HooksConfig {
+            timeout_secs: default_timeout_secs(),
+            max_concurrent: default_max_concurrent(),
+            retries: 0,
+            retry_delay_secs: default_retry_delay_secs(),
+            commands: HookCommands::new(),
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+// The commands set on a camera and its zones
+struct CameraHooks {
+    commands: HookCommands,
+    zones: Vec<(String, HookCommands)>,
+}
+
+// Runs of a command in progress and those waiting for one to finish
+#[derive(Default)]
+struct Runs {
+    running: usize,
+    waiting: VecDeque<Hook>,
+}
+
+/// Runs the hook commands for each event, the zones' commands first, then the camera's, then the global ones.
+/// Events without a command anywhere call the `notify_*.sh` scripts in the working directory, if they exist.
+pub struct ScriptSink {
+    config: HooksConfig,
+    cameras: HashMap<String, CameraHooks>,
+    scripts: Vec<&'static str>,
+    runs: HashMap<String, Arc<Mutex<Runs>>>,        // of each command
+}
+
+impl ScriptSink
+{
+    pub fn new(config: &HooksConfig, cameras: &[Camera]) -> ScriptSink
+    {
+        let mut scripts = Vec::new();
+        for (script, args) in SCRIPTS
+        {
+            if Path::new(script).exists()
+            {
+                info!("'{} {}' will be called.", script, args);
+                scripts.push(script);
+            }
+        }
+
+        check_commands("all cameras", &config.commands);
+        let mut camera_hooks = HashMap::new();
+        for camera in cameras
+        {
+            check_commands(&camera.name, &camera.hooks);
+            let mut zones = Vec::new();
+            for zone in camera.zones.iter().flatten()
+            {
+                check_commands(&format!("{} {}", &camera.name, &zone.name), &zone.hooks);
+                if !zone.hooks.is_empty() { zones.push((zone.name.clone(), zone.hooks.clone())); }
+            }
+            camera_hooks.insert(camera.name.clone(), CameraHooks { commands: camera.hooks.clone(), zones });
+        }
+
+        ScriptSink { config: config.clone(), cameras: camera_hooks, scripts, runs: HashMap::new() }
+    }
+
+    // The command lines for an event, most specific first: every zone it was in with a command, else the camera's, else the global one
+    fn commands(&self, event: &Event) -> Vec<String>
+    {
+        let name = event.name();
+        if let Some(hooks) = event.camera().and_then(|c| self.cameras.get(c))
+        {
+            let zones = event_zones(event);
+            let mut commands: Vec<String> = Vec::new();
+            for (_, zone_commands) in hooks.zones.iter().filter(|(zone, _)| zones.contains(zone))
+            {
+                if let Some(command) = zone_commands.get(name)
+                {
+                    if !commands.contains(command) { commands.push(command.clone()); }
+                }
+            }
+            if !commands.is_empty() { return commands; }
+            if let Some(command) = hooks.commands.get(name) { return vec![command.clone()]; }
+        }
+        self.config.commands.get(name).cloned().into_iter().collect()
+    }
+
+    // Run a hook in its own thread, or once one of them finishes if `key` already has too many running
+    fn start(&mut self, key: &str, hook: Hook, event: &Event)
+    {
+        let runs = Arc::clone(self.runs.entry(key.to_string()).or_default());
+
+        // A hung script only holds up its own events, not the other scripts or the bus
+        {
+            let mut r = runs.lock().unwrap();
+            if r.running >= self.config.max_concurrent
+            {
+                r.waiting.push_back(hook);
+                warn!("{} is already running {} times, {} waits its turn ({} waiting)", key, r.running, event.name(), r.waiting.len());
+                return;
+            }
+            r.running += 1;
+        }
+
+        // Each thread carries on with the waiting runs, in the order the events came
+        let config = self.config.clone();
+        thread::spawn(move || {
+            let mut hook = hook;
+            loop
+            {
+                hook.run_with_retries(&config);
+                let mut r = runs.lock().unwrap();
+                match r.waiting.pop_front() {
+                    Some(next) => { hook = next; }
+                    None => {
+                        r.running -= 1;
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl EventSink for ScriptSink
+{
+    fn name(&self) -> &str { "hook scripts" }
+
+    fn handle(&mut self, event: &Event)
+    {
+        let env = environment(event);
+        let payload = serde_json::to_string(event).unwrap_or_default();
+
+        let commands = self.commands(event);
+        if commands.is_empty()
+        {
+            if let Some((script, args)) = script_args(event)
+            {
+                if !self.scripts.contains(&script) { return; }
+                let hook = Hook { program: format!("./{}", script), args, env, payload };
+                self.start(script, hook, event);
+            }
+            return;
+        }
+
+        for command in commands
+        {
+            let mut words = expand(&command, &env).into_iter();
+            let program = match words.next() {
+                Some(program) => program,
+                None => { continue; }
+            };
+            let hook = Hook { program, args: words.collect(), env: env.clone(), payload: payload.clone() };
+            self.start(&command, hook, event);
+        }
+    }
+}
+
+// Warn about commands for events that don't exist, as they'd never run
+fn check_commands(owner: &str, commands: &HookCommands)
+{
+    for (event, command) in commands
+    {
+        if EVENT_NAMES.contains(&event.as_str()) { info!("'{}' will be called for {} on {}.", command, event, owner); }
+        else { warn!("Hook for unknown event '{}' on {}, the events are {}", event, owner, EVENT_NAMES.join(", ")); }
+    }
+}
+
+// The notify script for an event and its arguments
+fn script_args(event: &Event) -> Option<(&'static str, Vec<String>)>
+{
+    let script = match event {
+        Event::Started { first_image, .. } => ("notify_start_person.sh", vec![first_image.clone()]),
+        Event::Ended { event_id, part, video, best_image, preview_gif, contact_sheet, .. } => {
+            ("notify_end_person.sh", vec![best_image.clone(), video.clone(), event_id.clone(), part.to_string(), preview_gif.clone(), contact_sheet.clone()])
+        }
+        Event::TimelapseRollover { video, .. } => ("notify_timelapse_rollover.sh", vec![video.clone()]),
+        Event::CameraOnline { camera } => ("notify_camera.sh", vec![camera.clone(), "online".to_string(), String::new()]),
+        Event::CameraOffline { camera, reason } => ("notify_camera.sh", vec![camera.clone(), "offline".to_string(), reason.clone()]),
+        Event::Storage { kind, detail } => ("notify_storage.sh", vec![kind.name().to_string(), detail.clone()]),
+        Event::BestImageUpdated { .. } | Event::Digest { .. } => { return None; }
+    };
+    Some(script)
+}
+
+fn event_zones(event: &Event) -> &[String]
+{
+    match event {
+        Event::Started { zones, .. } | Event::Ended { zones, .. } => zones,
+        _ => &[],
+    }
+}
+
+/// Fill in a template's `{field}`s with an event's values, passed through `escape`.
+/// The fields are the environment variables without `SC_`, in lower case.
+pub fn fill(template: &str, env: &[(&'static str, String)], escape: impl Fn(&str) -> String) -> String
+{
+    let mut filled = template.to_string();
+    for (name, value) in env
+    {
+        let field = format!("{{{}}}", name.trim_start_matches("SC_").to_lowercase());
+        filled = filled.replace(&field, &escape(value));
+    }
+    filled
+}
+
+// Split a command line into words and fill in each word. No shell is involved so a file name with spaces stays one argument,
+// use `sh -c '...'` for pipes and the like.
+fn expand(command: &str, env: &[(&'static str, String)]) -> Vec<String>
+{
+    split_words(command).iter().map(|word| fill(word, env, |v| v.to_string())).collect()
+}
+
+/// Key fields of an event for the scripts, so they don't all need to parse the JSON. They're also the fields for templates.
+pub fn environment(event: &Event) -> Vec<(&'static str, String)>
+{
+    let mut env = vec![("SC_EVENT", event.name().to_string())];
+    if let Some(camera) = event.camera() { env.push(("SC_CAMERA", camera.to_string())); }
+    match event {
+        Event::Started { event_id, time, first_image, zones, label, score, .. } => {
+            env.push(("SC_EVENT_ID", event_id.clone()));
+            env.push(("SC_TIME", time.clone()));
+            env.push(("SC_FIRST_IMAGE", first_image.clone()));
+            env.push(("SC_ZONE", zones.join(",")));
+            env.push(("SC_LABEL", label.clone()));
+            env.push(("SC_SCORE", score.to_string()));
+        }
+        Event::BestImageUpdated { event_id, part, time, score, .. } => {
+            env.push(("SC_EVENT_ID", event_id.clone()));
+            env.push(("SC_PART", part.to_string()));
+            env.push(("SC_TIME", time.clone()));
+            env.push(("SC_SCORE", score.to_string()));
+        }
+        Event::Ended { event_id, part, start, end, video, best_image, preview_gif, contact_sheet, sidecar, zones, labels, max_score, .. } => {
+            env.push(("SC_EVENT_ID", event_id.clone()));
+            env.push(("SC_PART", part.to_string()));
+            env.push(("SC_START", start.clone()));
+            env.push(("SC_END", end.clone()));
+            env.push(("SC_VIDEO", video.clone()));
+            env.push(("SC_BEST_IMAGE", best_image.clone()));
+            env.push(("SC_PREVIEW_GIF", preview_gif.clone()));
+            env.push(("SC_CONTACT_SHEET", contact_sheet.clone()));
+            env.push(("SC_SIDECAR", sidecar.clone()));
+            env.push(("SC_ZONE", zones.join(",")));
+            env.push(("SC_LABEL", labels.join(",")));
+            env.push(("SC_SCORE", max_score.to_string()));
+        }
+        Event::TimelapseRollover { video, .. } => { env.push(("SC_VIDEO", video.clone())); }
+        Event::CameraOnline { .. } => {}
+        Event::CameraOffline { reason, .. } => { env.push(("SC_REASON", reason.clone())); }
+        Event::Storage { kind, detail } => {
+            env.push(("SC_STORAGE_EVENT", kind.name().to_string()));
+            env.push(("SC_DETAIL", detail.clone()));
+        }
+        Event::Digest { start, end, events } => {
+            env.push(("SC_START", start.clone()));
+            env.push(("SC_END", end.clone()));
+            env.push(("SC_COUNT", events.len().to_string()));
+        }
+    }
+    env
+}
+
+// One call of a script for an event
+struct Hook {
+    program: String,
+    args: Vec<String>,
+    env: Vec<(&'static str, String)>,
+    payload: String,        // the event as JSON, given on stdin
+}
+
+enum Outcome {
+    Exited(ExitStatus),
+    TimedOut,
+}
+
+impl Hook
+{
+    fn run_with_retries(&self, config: &HooksConfig)
+    {
+        for attempt in 0..=config.retries
+        {
+            if attempt > 0
+            {
+                thread::sleep(Duration::from_secs(config.retry_delay_secs));
+                info!("Retrying {}, attempt {} of {}", &self.program, attempt + 1, config.retries + 1);
+            }
+            match self.run(Duration::from_secs(config.timeout_secs)) {
+                Ok(Outcome::Exited(status)) if status.success() => {
+                    info!("{} finished", &self.program);
+                    return;
+                }
+                Ok(Outcome::Exited(status)) => { error!("{} failed, {}", &self.program, status); }
+                Ok(Outcome::TimedOut) => { error!("{} timed out after {} seconds and was killed", &self.program, config.timeout_secs); }
+                Err(e) => { error!("Error calling script {}: {}", &self.program, e); }
+            }
+        }
+    }
+
+    fn run(&self, timeout: Duration) -> std::io::Result<Outcome>
+    {
+        info!("Calling '{} {}'", &self.program, self.args.join(" "));
+        // In its own process group so whatever it starts can be killed along with it
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .envs(self.env.iter().map(|(k, v)| (*k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+
+        // Scripts that don't read stdin close it early, that's not an error
+        if let Some(mut stdin) = child.stdin.take()
+        {
+            let payload = self.payload.clone();
+            thread::spawn(move || { let _ = stdin.write_all(payload.as_bytes()); });
+        }
+        let stdout = child.stdout.take().map(|s| log_output(&self.program, "stdout", s));
+        let stderr = child.stderr.take().map(|s| log_output(&self.program, "stderr", s));
+
+        let outcome = wait_timeout(&mut child, timeout)?;
+
+        // The pipes close once the script has gone. After a timeout anything it started that left its process group
+        // may still hold them, so the output is left to finish logging by itself.
+        if let Outcome::Exited(_) = &outcome
+        {
+            for reader in [stdout, stderr].into_iter().flatten() { let _ = reader.join(); }
+        }
+        Ok(outcome)
+    }
+}
+
+// Wait for a script to exit, killing it and everything it started if it takes too long, e.g. a hung upload.
+// It's always waited on so it doesn't become a zombie.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Outcome>
+{
+    let started = Instant::now();
+    loop
+    {
+        if let Some(status) = child.try_wait()? { return Ok(Outcome::Exited(status)); }
+        if started.elapsed() >= timeout
+        {
+            // The group has the script's pid as its id
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL); }
+            child.wait()?;
+            return Ok(Outcome::TimedOut);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Log a script's output line by line as it comes
+fn log_output<R: Read + Send + 'static>(program: &str, stream: &'static str, output: R) -> thread::JoinHandle<()>
+{
+    let program = program.to_string();
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok)
+        {
+            match stream {
+                "stderr" => warn!("{} {}: {}", &program, stream, line),
+                _ => info!("{} {}: {}", &program, stream, line),
+            }
+        }
+    })
+}
+
+// Words of a command line, '' or "" quote spaces
+fn split_words(command: &str) -> Vec<String>
+{
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    for c in command.chars()
+    {
+        match quote {
+            Some(q) if c == q => { quote = None; }
+            Some(_) => { word.push(c); }
+            None if c == '\'' || c == '"' => { quote = Some(c); in_word = true; }
+            None if c.is_whitespace() => {
+                if in_word { words.push(std::mem::take(&mut word)); }
+                in_word = false;
+            }
+            None => { word.push(c); in_word = true; }
+        }
+    }
+    if in_word { words.push(word); }
+    words
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs;
+    use crate::test_util::{temp_path, wait_for};
+
+    fn sink(max_concurrent: usize, timeout_secs: u64, command: &str) -> ScriptSink
+    {
+        let mut config = HooksConfig { max_concurrent, timeout_secs, ..HooksConfig::default() };
+        config.commands.insert("camera_online".to_string(), command.to_string());
+        ScriptSink::new(&config, &[])
+    }
+
+    fn lines(path: &Path) -> Vec<String>
+    {
+        fs::read_to_string(path).unwrap_or_default().lines().map(|l| l.to_string()).collect()
+    }
+
+    // Whether a process is still running, a killed one may linger as a zombie until it's reaped
+    fn running(pid: &str) -> bool
+    {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| stat.rsplit(')').next().is_some_and(|rest| !rest.trim_start().starts_with('Z')))
+    }
+
+    #[test]
+    fn runs_beyond_max_concurrent_wait_their_turn()
+    {
+        let out = temp_path("hooks_queue");
+        let mut sink = sink(1, 10, &format!("sh -c 'echo start $SC_CAMERA >> {0}; sleep 0.2; echo end $SC_CAMERA >> {0}'", out.display()));
+        for camera in ["A", "B", "C"] { sink.handle(&Event::CameraOnline { camera: camera.to_string() }); }
+
+        wait_for(|| lines(&out).len() >= 6, Duration::from_secs(10));
+        assert_eq!(lines(&out), ["start A", "end A", "start B", "end B", "start C", "end C"]);
+        let _ = fs::remove_file(&out);
+    }
+
+    #[test]
+    fn a_timed_out_script_is_killed_with_its_children()
+    {
+        let out = temp_path("hooks_timeout");
+        let mut sink = sink(1, 1, &format!("sh -c '(sleep 30; echo late >> {0}) & echo $! >> {0}; wait'", out.display()));
+        sink.handle(&Event::CameraOnline { camera: "A".to_string() });
+
+        // The script's child writes nothing until long after the timeout, by which time it should be gone
+        assert!(wait_for(|| !lines(&out).is_empty(), Duration::from_secs(10)));
+        let child = lines(&out)[0].clone();
+        assert!(wait_for(|| !running(&child), Duration::from_secs(10)));
+        assert_eq!(lines(&out), [child]);
+        let _ = fs::remove_file(&out);
+    }
+
+    #[test]
+    fn split_words_keeps_quoted_spaces()
+    {
+        assert_eq!(split_words("./notify.sh  {video} {best_image}"), ["./notify.sh", "{video}", "{best_image}"]);
+        assert_eq!(split_words("sh -c 'echo $SC_CAMERA | cat'"), ["sh", "-c", "echo $SC_CAMERA | cat"]);
+        assert_eq!(split_words(r#"say "it's {camera}" now"#), ["say", "it's {camera}", "now"]);
+        assert_eq!(split_words("empty '' arg"), ["empty", "", "arg"]);
+        assert!(split_words("   ").is_empty());
+    }
+
+    #[test]
+    fn expand_keeps_each_field_one_argument()
+    {
+        let env = vec![("SC_CAMERA", "Front Door".to_string()), ("SC_VIDEO", "captures/a b.mp4".to_string())];
+        assert_eq!(expand("./notify.sh {camera} {video} {unknown}", &env), ["./notify.sh", "Front Door", "captures/a b.mp4", "{unknown}"]);
+        assert_eq!(expand("echo '{camera}: {video}'", &env), ["echo", "Front Door: captures/a b.mp4"]);
+    }
+
+    #[test]
+    fn check_rejects_no_runs_at_once()
+    {
+        assert!(HooksConfig::default().check().is_ok());
+        assert!(HooksConfig { max_concurrent: 0, ..HooksConfig::default() }.check().is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use rusqlite::{Connection, params, ToSql};
+
+use crate::metadata::{EventMetadata, SegmentMetadata};
+use crate::paths;
+use crate::retention::Category;
+use crate::storage::is_part;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    event_id TEXT NOT NULL,
+    part INTEGER NOT NULL,
+    camera TEXT NOT NULL,
+    start TEXT,
+    end TEXT,
+    start_ts INTEGER,
+    end_ts INTEGER,
+    zones TEXT,
+    labels TEXT,
+    max_score REAL,
+    video TEXT,
+    best_image TEXT,
+    sidecar TEXT,
+    PRIMARY KEY (event_id, part)
+);
+CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    camera TEXT,
+    event_id TEXT,
+    start TEXT,
+    end TEXT,
+    start_ts INTEGER,
+    end_ts INTEGER,
+    size INTEGER
+);
+CREATE INDEX IF NOT EXISTS events_camera_start ON events (camera, start_ts);
+CREATE INDEX IF NOT EXISTS files_camera_start ON files (camera, start_ts);
+";
+
+/// Filters for the events query, all optional
+pub struct EventFilter {
+    pub camera: Option<String>,
+    pub zone: Option<String>,
+    pub label: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub min_score: Option<f32>,
+    pub limit: u32,
+}
+
+static CONNECTION: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+fn open() -> rusqlite::Result<Connection>
+{
+    let conn = Connection::open(format!("{}/index.db", paths::root()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+// Use the one connection, opening it and creating the schema the first time. A failed open is tried again next time.
+fn with_connection<T>(f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> rusqlite::Result<T>
+{
+    let conn = match CONNECTION.get() {
+        Some(conn) => conn,
+        None => {
+            let conn = open()?;
+            CONNECTION.get_or_init(|| Mutex::new(conn))
+        }
+    };
+    let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut conn)
+}
+
+/// Add a finished person recording, along with its files, to the index
+pub fn record_event(metadata: &EventMetadata, sidecar: &str)
+{
+    let r = with_connection(|conn| insert_event(conn, metadata, sidecar));
+    if let Err(e) = r { error!("Can't index event {}: {}", &metadata.event_id, e); }
+}
+
+/// Add a closed continuous segment or timelapse file to the index
+pub fn record_segment(kind: &str, metadata: &SegmentMetadata)
+{
+    let r = with_connection(|conn| insert_segment(conn, kind, metadata));
+    if let Err(e) = r { error!("Can't index {}: {}", &metadata.video, e); }
+}
+
+/// Remove a deleted file from the index
+pub fn forget(path: &Path)
+{
+    let path = path.to_string_lossy().to_string();
+    let r = with_connection(|conn| {
+        conn.execute("DELETE FROM files WHERE path = ?1", params![&path])?;
+        conn.execute("DELETE FROM events WHERE sidecar = ?1", params![&path])
+    });
+    if let Err(e) = r { error!("Can't remove {} from the index: {}", &path, e); }
+}
+
+fn insert_event(conn: &Connection, metadata: &EventMetadata, sidecar: &str) -> rusqlite::Result<()>
+{
+    let labels = metadata.labels();
+    let max_score = metadata.max_score();
+    let zones: Vec<&str> = metadata.zones.iter().map(|z| z.as_str()).collect();
+    let labels: Vec<&str> = labels.iter().map(|l| l.as_str()).collect();
+
+    conn.execute(
+        "INSERT OR REPLACE INTO events (event_id, part, camera, start, end, start_ts, end_ts, zones, labels, max_score, video, best_image, sidecar)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            &metadata.event_id, metadata.part, &metadata.camera, &metadata.start, &metadata.end,
+            timestamp(&metadata.start), timestamp(&metadata.end), zones.join(","), labels.join(","), max_score,
+            &metadata.video, metadata.best_image.as_deref().unwrap_or(&metadata.first_image), sidecar,
+        ])?;
+
+    let files = [
+        ("video", Some(metadata.video.as_str())),
+        ("first_image", Some(metadata.first_image.as_str())),
+        ("best_image", metadata.best_image.as_deref()),
+        ("preview_gif", metadata.preview_gif.as_deref()),
+        ("contact_sheet", metadata.contact_sheet.as_deref()),
+        ("sidecar", Some(sidecar)),
+    ];
+    for (kind, path) in files
+    {
+        if let Some(path) = path.filter(|p| !p.is_empty())
+        {
+            insert_file(conn, path, kind, &metadata.camera, Some(&metadata.event_id), &metadata.start, &metadata.end)?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_segment(conn: &Connection, kind: &str, metadata: &SegmentMetadata) -> rusqlite::Result<()>
+{
+    insert_file(conn, &metadata.video, kind, &metadata.camera, None, &metadata.start, &metadata.end)
+}
+
+fn insert_file(conn: &Connection, path: &str, kind: &str, camera: &str, event_id: Option<&str>, start: &str, end: &str) -> rusqlite::Result<()>
+{
+    let size = fs::metadata(path).map(|m| m.len() as i64).ok();
+    conn.execute(
+        "INSERT OR REPLACE INTO files (path, kind, camera, event_id, start, end, start_ts, end_ts, size)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![path, kind, camera, event_id, start, end, timestamp(start), timestamp(end), size])?;
+    Ok(())
+}
+
+/// Rebuild the index from the sidecars and files under the storage root, returns the number of events and files indexed
+pub fn rebuild() -> Result<(usize, usize), Box<dyn Error>>
+{
+    with_connection(rebuild_in).map_err(|e| e.into())
+}
+
+fn rebuild_in(conn: &mut Connection) -> rusqlite::Result<(usize, usize)>
+{
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM events", [])?;
+    tx.execute("DELETE FROM files", [])?;
+
+    let mut events = 0;
+    for category in Category::all()
+    {
+        for path in paths::files(category)
+        {
+            let name = path.to_string_lossy().to_string();
+            if !name.ends_with(".json") || is_part(&path) { continue; }
+            match category {
+                Category::PeopleVideo => {
+                    match EventMetadata::load(&name) {
+                        Ok(metadata) => { insert_event(&tx, &metadata, &name)?; events += 1; }
+                        Err(e) => { warn!("Skipping {}: {}", &name, e); }
+                    }
+                }
+                Category::Continuous | Category::Timelapse => {
+                    match SegmentMetadata::load(&name) {
+                        Ok(metadata) => { insert_segment(&tx, kind(category), &metadata)?; }
+                        Err(e) => { warn!("Skipping {}: {}", &name, e); }
+                    }
+                }
+                Category::PeoplePhotos => {}
+            }
+        }
+    }
+
+    // Files without a sidecar, e.g. from before sidecars were written, are indexed by their name
+    for category in Category::all()
+    {
+        for path in paths::files(category)
+        {
+            let name = path.to_string_lossy().to_string();
+            let indexed: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM files WHERE path = ?1)", params![&name], |row| row.get(0))?;
+            if indexed || is_part(&path) || name.ends_with(".keep") || name.ends_with(".db") { continue; }
+            let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            if let Some((camera, time)) = split_name(&stem)
+            {
+                let camera = paths::camera_directory(category, &path).unwrap_or(camera);
+                let time = time.to_rfc3339();
+                insert_file(&tx, &name, kind(category), &camera, None, &time, &time)?;
+            }
+        }
+    }
+
+    let files = tx.query_row("SELECT COUNT(*) FROM files", [], |row| row.get::<_, i64>(0))? as usize;
+    tx.commit()?;
+    Ok((events, files))
+}
+
+/// Print the events matching a filter, newest first
+pub fn print_events(filter: &EventFilter) -> Result<(), Box<dyn Error>>
+{
+    with_connection(|conn| query_events(conn, filter)).map_err(|e| e.into())
+}
+
+fn query_events(conn: &Connection, filter: &EventFilter) -> rusqlite::Result<()>
+{
+    let mut sql = "SELECT start, end, camera, event_id, part, zones, labels, max_score, video, best_image FROM events WHERE 1 = 1".to_string();
+    let mut args: Vec<Box<dyn ToSql>> = Vec::new();
+    if let Some(camera) = &filter.camera { sql += " AND camera = ?"; args.push(Box::new(camera.clone())); }
+    if let Some(zone) = &filter.zone { sql += " AND ',' || zones || ',' LIKE ?"; args.push(Box::new(format!("%,{},%", zone))); }
+    if let Some(label) = &filter.label { sql += " AND ',' || labels || ',' LIKE ?"; args.push(Box::new(format!("%,{},%", label))); }
+    if let Some(since) = &filter.since { sql += " AND end_ts >= ?"; args.push(Box::new(since.timestamp())); }
+    if let Some(until) = &filter.until { sql += " AND start_ts <= ?"; args.push(Box::new(until.timestamp())); }
+    if let Some(score) = filter.min_score { sql += " AND max_score >= ?"; args.push(Box::new(score as f64)); }
+    sql += " ORDER BY start_ts DESC LIMIT ?";
+    args.push(Box::new(filter.limit));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(args.iter()), |row| {
+        Ok(format!("{}  {}  {:<12} {} part {}  zones: {}  labels: {}  score: {:.2}  {}  {}",
+            short_time(&row.get::<_, String>(0)?), short_time(&row.get::<_, String>(1)?),
+            row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, u32>(4)?,
+            row.get::<_, String>(5)?, row.get::<_, String>(6)?, row.get::<_, f64>(7)?,
+            row.get::<_, String>(8)?, row.get::<_, String>(9)?))
+    })?;
+
+    let mut count = 0;
+    for row in rows
+    {
+        println!("{}", row?);
+        count += 1;
+    }
+    println!("{} events", count);
+    Ok(())
+}
+
+fn kind(category: Category) -> &'static str
+{
+    match category {
+        Category::PeopleVideo => "video",
+        Category::PeoplePhotos => "photo",
+        Category::Timelapse => "timelapse",
+        Category::Continuous => "continuous",
+    }
+}
+
+// Seconds since the epoch of an rfc3339 time, for comparisons
+fn timestamp(time: &str) -> Option<i64>
+{
+    DateTime::parse_from_rfc3339(time).ok().map(|t| t.timestamp())
+}
+
+fn short_time(time: &str) -> String
+{
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(t) => t.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => time.to_string(),
+    }
+}
+
+// Split a capture's name into the camera and time, e.g. `Door20230519-101500-best` is Door at 2023-05-19 10:15:00
+fn split_name(stem: &str) -> Option<(String, DateTime<Local>)>
+{
+    let bytes = stem.as_bytes();
+    for i in 0..stem.len()
+    {
+        if i + 15 > bytes.len() { break; }
+        let candidate = &bytes[i..i + 15];
+        let digits = candidate.iter().enumerate().all(|(j, b)| if j == 8 { *b == b'-' } else { b.is_ascii_digit() });
+        if !digits || !stem.is_char_boundary(i) { continue; }
+
+        // A camera name can itself end in digits, so carry on to the next candidate
+        let time = match NaiveDateTime::parse_from_str(&stem[i..i + 15], "%Y%m%d-%H%M%S").ok().and_then(|t| Local.from_local_datetime(&t).earliest()) {
+            Some(time) => time,
+            None => { continue; }
+        };
+        return Some((stem[..i].to_string(), time));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Local>
+    {
+        let time = NaiveDateTime::parse_from_str(text, "%Y%m%d-%H%M%S").unwrap();
+        Local.from_local_datetime(&time).earliest().unwrap()
+    }
+
+    #[test]
+    fn split_name_finds_the_camera_and_time()
+    {
+        assert_eq!(split_name("Door20230519-101500"), Some(("Door".to_string(), at("20230519-101500"))));
+        assert_eq!(split_name("Door20230519-101500-002"), Some(("Door".to_string(), at("20230519-101500"))));
+        assert_eq!(split_name("Cam120230519-101500"), Some(("Cam1".to_string(), at("20230519-101500"))));
+        assert_eq!(split_name("Front Door20230519-101500-first"), Some(("Front Door".to_string(), at("20230519-101500"))));
+    }
+
+    #[test]
+    fn split_name_skips_candidates_that_arent_times()
+    {
+        assert_eq!(split_name("99999999-999999Door20230519-101500"),
+                   Some(("99999999-999999Door".to_string(), at("20230519-101500"))));
+        assert_eq!(split_name("Door"), None);
+        assert_eq!(split_name("Door20231319-101500"), None);
+        assert_eq!(split_name("Caméra20230519-101500"), Some(("Caméra".to_string(), at("20230519-101500"))));
+    }
+}
@@ -1,5 +1,32 @@
 mod config;
+mod continuous;
+mod email;
+mod encryption;
+mod events;
+mod evidence;
+mod export;
+mod hooks;
+mod index;
+mod manifest;
+mod best;
 mod camera;
+mod metadata;
+mod mqtt;
+mod overlay;
+mod paths;
+mod preview;
+mod recorder;
+mod recovery;
+mod retention;
+mod status;
+mod storage;
+mod subtitles;
+#[cfg(test)]
+mod test_util;
+mod throttle;
+mod timelapse;
+mod tracker;
+mod webhook;
 
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -18,6 +45,14 @@ use opencv::{
 use moonfire_tflite::*;
 use crate::camera::{Camera, Point};
 use crate::config::{CliConfig, Config, USAGE};
+use crate::email::EmailSink;
+use crate::events::Event;
+use crate::hooks::ScriptSink;
+use crate::mqtt::MqttSink;
+use crate::paths::{default_storage_root, PathTemplates};
+use crate::storage::StorageConfig;
+use crate::throttle::Throttled;
+use crate::webhook::WebhookSink;
 
 #[macro_use] extern crate log;
 
@@ -26,11 +61,6 @@ use crate::config::{CliConfig, Config, USAGE};
 
 fn main() -> Result<()>
 {
-    // Notification events
-    let mut notify_start_person = false;
-    let mut notify_end_person = false;
-    let mut notify_timelapse_rollover = false;
-
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -50,43 +80,129 @@ fn main() -> Result<()>
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    // Check directories
-    if !Path::new("captures/people/video").exists()
+    // The config file sets where captures are stored, so it's loaded before the subcommands run
+    let file_config = config.flag_config.as_ref().map(|f| Config::load(f).expect(&format!("Can't load config file {}", f)));
+    match &file_config
     {
-        error!("'captures/people/video' directory does not exist at this location.");
-        panic!("Unable to proceed");
+        Some(c) => {
+            paths::init(&c.storage_root, &c.paths);
+            if let Some(encryption) = &c.encryption
+            {
+                if let Err(e) = encryption::init(encryption)
+                {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => { paths::init(&default_storage_root(), &PathTemplates::default()); }
     }
-    if !Path::new("captures/people/photos").exists()
+
+    // Subcommands, these don't need the EdgeTPU
+    if config.cmd_export
     {
-        error!("'captures/people/photos' directory does not exist at this location.");
-        panic!("Unable to proceed");
+        match export::export(&config.arg_camera, &config.arg_start, &config.arg_end, config.flag_from.as_deref(), config.flag_output.as_deref(), config.flag_overlay)
+        {
+            Ok(filename) => { info!("Exported to {}", filename); }
+            Err(e) => {
+                error!("Export failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
     }
-    if config.flag_timelapse && !Path::new("captures/timelapse").exists()
+    if config.cmd_events
     {
-        error!("'captures/timelapse' directory does not exist at this location.");
-        panic!("Unable to proceed");
+        if let Err(e) = list_events(&config)
+        {
+            error!("Events query failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
     }
 
-    // Check notify scripts
-    if Path::new("notify_start_person.sh").exists()
+    if config.cmd_decrypt
     {
-        info!("'notify_start_person.sh <first-image-file>' will be called.");
-        notify_start_person = true;
+        let identity = match &config.flag_identity {
+            Some(identity) => identity,
+            None => {
+                error!("Decrypting needs the identity file with the private key, use --identity");
+                std::process::exit(1);
+            }
+        };
+        match encryption::decrypt(&config.arg_file, identity)
+        {
+            Ok(n) => { info!("Decrypted {} of {} files", n, config.arg_file.len()); }
+            Err(e) => {
+                error!("Decrypt failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
     }
-    if Path::new("notify_end_person.sh").exists()
+
+    if config.cmd_export_evidence
     {
-        info!("'notify_end_person.sh <best-image-file> <video-file>' will be called.");
-        notify_end_person = true;
+        match evidence::export_evidence(&config.arg_event_id, config.flag_output.as_deref())
+        {
+            Ok(bundle) => { info!("Evidence bundle written to {}", bundle); }
+            Err(e) => {
+                error!("Evidence export failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
     }
-    if config.flag_timelapse && Path::new("notify_timelapse_rollover.sh").exists()
+    if config.cmd_verify
     {
-        info!("'notify_timelapse_rollover.sh <video-file>' will be called.");
-        notify_timelapse_rollover = true;
+        match evidence::verify(&config.arg_bundle, config.flag_key.as_deref())
+        {
+            Ok(true) => { info!("{} verified.", &config.arg_bundle); }
+            Ok(false) => {
+                error!("{} FAILED verification.", &config.arg_bundle);
+                std::process::exit(2);
+            }
+            Err(e) => {
+                error!("Can't verify {}: {}", &config.arg_bundle, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Event sinks, subscribed before anything is published. MQTT isn't throttled as it keeps the cameras' state.
+    let hooks = file_config.as_ref().map(|c| c.hooks.clone()).unwrap_or_default();
+    let cameras = file_config.as_ref().map(|c| c.cameras.clone()).unwrap_or_default();
+    events::subscribe(Box::new(Throttled::new(Box::new(ScriptSink::new(&hooks, &cameras)), &hooks.throttle, &cameras)));
+    for webhook in file_config.iter().flat_map(|c| &c.webhooks)
+    {
+        match WebhookSink::new(webhook) {
+            Ok(sink) => { events::subscribe(Box::new(Throttled::new(Box::new(sink), &webhook.throttle, &cameras))); }
+            Err(e) => { error!("Can't set up webhook {}: {}", &webhook.name, e); }
+        }
     }
+    if let Some(mqtt) = file_config.as_ref().and_then(|c| c.mqtt.as_ref())
+    {
+        events::subscribe(Box::new(MqttSink::new(mqtt, &cameras)));
+    }
+    if let Some(email) = file_config.as_ref().and_then(|c| c.email.as_ref())
+    {
+        match EmailSink::new(email) {
+            Ok(sink) => { events::subscribe(Box::new(Throttled::new(Box::new(sink), &email.throttle, &cameras))); }
+            Err(e) => { error!("Can't set up email: {}", e); }
+        }
+    }
+
+    // Directories are created as captures are written
+    info!("Captures are stored in '{}'.", paths::root());
+    if let Err(e) = manifest::open() { error!("Can't open the manifest, files won't be recorded in it: {}", e); }
+    if let Err(e) = manifest::device_key() { error!("Can't load the device key: {}", e); }
+    recovery::recover();
+
 
 
     // Moonfire-tflite
-    static EDGETPU_MODEL: &'static [u8] = include_bytes!("../ssdlite_mobiledet_coco_qat_postprocess_edgetpu.tflite");
+    static EDGETPU_MODEL: &[u8] = include_bytes!("../ssdlite_mobiledet_coco_qat_postprocess_edgetpu.tflite");
     let m = Model::from_static(EDGETPU_MODEL).unwrap();
     let mut builder = Interpreter::builder();
 
@@ -114,13 +230,14 @@ fn main() -> Result<()>
     // Wrap interpreter
     let interpreter = Arc::new(Mutex::new(interpreter));
 
-    match config.flag_config
+    match file_config
     {
-        Some(f) => {
-            let config = Config::load(&f).expect(&format!("Can't load config file {}", &f));
-
+        Some(config) => {
             info!("Config: {:?}", &config);
 
+            retention::start(config.cameras.clone());
+            storage::start_monitor(config.storage.clone(), config.cameras.iter().map(|c| c.name.clone()).collect());
+
             let mut threads = vec![];
 
             for c in config.cameras
@@ -128,10 +245,15 @@ fn main() -> Result<()>
                 let interpreter = Arc::clone(&interpreter);
                 threads.push(thread::spawn(move || {
                     loop {
-                        if let Err(e) = c.run(Arc::clone(&interpreter), notify_start_person, notify_end_person, notify_timelapse_rollover)
-                        {
-                            error!("{}: {:?}", c.name, e);
-                        }
+                        let reason = match c.run(Arc::clone(&interpreter)) {
+                            Ok(_) => "stream ended".to_string(),
+                            Err(e) => {
+                                error!("{}: {:?}", c.name, e);
+                                e.message
+                            }
+                        };
+                        status::offline(&c.name);
+                        events::publish(Event::CameraOffline { camera: c.name.clone(), reason });
                         info!("Camera \'{}\' disconnected, will reconnect in 10s...", &c.name);
                         sleep(Duration::from_secs(10));
                     }
@@ -148,12 +270,14 @@ fn main() -> Result<()>
             // Create Single Camera instance when no config file
             let mut camera = Camera::new(&config.arg_video_source);
             if config.flag_monitor { camera.monitor = true; }
+            if config.flag_timelapse { camera.timelapse = true; }
             if let Some(polygon_file) = &config.flag_polygon
             {
                 camera.boundary = Some(read_polygon_file(polygon_file));
             }
+            storage::start_monitor(StorageConfig::default(), vec![camera.name.clone()]);
 
-            camera.run(interpreter, notify_start_person, notify_end_person, notify_timelapse_rollover)?;
+            camera.run(interpreter)?;
         }
     }
 
@@ -161,6 +285,27 @@ fn main() -> Result<()>
 }
 
 
+fn list_events(config: &CliConfig) -> std::result::Result<(), Box<dyn std::error::Error>>
+{
+    if config.flag_rebuild
+    {
+        let (events, files) = index::rebuild()?;
+        info!("Index rebuilt with {} events and {} files.", events, files);
+    }
+
+    let filter = index::EventFilter {
+        camera: config.flag_camera.clone(),
+        zone: config.flag_zone.clone(),
+        label: config.flag_label.clone(),
+        since: config.flag_since.as_deref().map(export::parse_time).transpose()?,
+        until: config.flag_until.as_deref().map(export::parse_time).transpose()?,
+        min_score: config.flag_min_score,
+        limit: config.flag_limit,
+    };
+    index::print_events(&filter)
+}
+
+
 #[derive(Debug, Deserialize)]
 struct CsvRecord {
     x: i32,
@@ -0,0 +1,242 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::Local;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::events::StorageEvent;
+use crate::paths;
+use crate::storage::report;
+
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A finalised file in the manifest. Each entry's hash covers the hash of the one before,
+/// so an entry can't be edited or removed without breaking the chain from there on.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    pub seq: u64,
+    pub time: String,
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    pub prev: String,       // hash of the previous entry
+    pub hash: String,
+}
+
+impl ManifestEntry
+{
+    fn new(seq: u64, path: &str, sha256: String, size: u64, prev: &str) -> ManifestEntry
+    {
+        let mut entry = ManifestEntry {
+            seq,
+            time: Local::now().to_rfc3339(),
+            path: path.to_string(),
+            sha256,
+            size,
+            prev: prev.to_string(),
+            hash: String::new(),
+        };
+        entry.hash = entry.chain_hash();
+        entry
+    }
+
+    fn chain_hash(&self) -> String
+    {
+        let fields = format!("{}\n{}\n{}\n{}\n{}\n{}", self.prev, self.seq, self.time, self.path, self.sha256, self.size);
+        hex::encode(Sha256::digest(fields.as_bytes()))
+    }
+}
+
+// Next sequence number and the hash it chains from, None until the manifest is opened
+struct Head {
+    seq: u64,
+    hash: String,
+}
+
+static HEAD: Mutex<Option<Head>> = Mutex::new(None);
+
+/// The manifest of every finalised file, one JSON entry per line
+pub fn manifest_filename() -> String
+{
+    format!("{}/manifest.jsonl", paths::root())
+}
+
+/// Start adding finalised files to the manifest, carrying on its chain. A broken chain is reported but recording carries on.
+pub fn open() -> Result<(), Box<dyn Error>>
+{
+    let entries = load(&manifest_filename())?;
+    if let Err(e) = check_chain(&entries) { report(StorageEvent::ManifestBroken, &e); }
+
+    let head = match entries.last() {
+        Some(last) => Head { seq: last.seq + 1, hash: last.hash.clone() },
+        None => Head { seq: 0, hash: GENESIS.to_string() },
+    };
+    info!("Manifest {} has {} entries.", manifest_filename(), entries.len());
+    *HEAD.lock().unwrap() = Some(head);
+    Ok(())
+}
+
+/// Add a finalised file to the manifest, if it's open
+pub fn record(filename: &str)
+{
+    if HEAD.lock().unwrap().is_none() { return; }
+
+    // Hashed before taking the lock, so the writers don't queue up behind a long video
+    let (sha256, size) = match hash_file(filename) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Can't hash {} for the manifest: {}", filename, e);
+            return;
+        }
+    };
+
+    let mut head = HEAD.lock().unwrap();
+    if let Some(head) = head.as_mut()
+    {
+        let entry = ManifestEntry::new(head.seq, filename, sha256, size, &head.hash);
+        match append(&entry) {
+            Ok(_) => {
+                head.seq += 1;
+                head.hash = entry.hash;
+            }
+            Err(e) => { error!("Can't add {} to the manifest: {}", filename, e); }
+        }
+    }
+}
+
+fn append(entry: &ManifestEntry) -> Result<(), Box<dyn Error>>
+{
+    let line = serde_json::to_string(entry)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(manifest_filename())?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Read a manifest, a missing one is empty
+pub fn load(filename: &str) -> Result<Vec<ManifestEntry>, Box<dyn Error>>
+{
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => { return Ok(Vec::new()); }
+        Err(e) => { return Err(e.into()); }
+    };
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate()
+    {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        entries.push(serde_json::from_str(&line).map_err(|e| format!("{} line {}: {}", filename, i + 1, e))?);
+    }
+    Ok(entries)
+}
+
+/// Check every entry follows on from the one before, from the start of the chain
+pub fn check_chain(entries: &[ManifestEntry]) -> Result<(), String>
+{
+    let mut prev = GENESIS;
+    for (i, entry) in entries.iter().enumerate()
+    {
+        if entry.seq != i as u64 { return Err(format!("entry {} is numbered {}", i, entry.seq)); }
+        if entry.prev != prev { return Err(format!("entry {} doesn't follow on from the one before", entry.seq)); }
+        if entry.hash != entry.chain_hash() { return Err(format!("entry {} ({}) has been altered", entry.seq, entry.path)); }
+        prev = &entry.hash;
+    }
+    Ok(())
+}
+
+/// SHA-256 and size of a file
+pub fn hash_file(filename: &str) -> io::Result<(String, u64)>
+{
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut BufReader::new(File::open(filename)?), &mut hasher)?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+/// The device's signing key, made the first time it's needed. The public half is written to `device.pub`
+/// alongside, to give to whoever will check the bundles.
+pub fn device_key() -> Result<SigningKey, Box<dyn Error>>
+{
+    let filename = format!("{}/device.key", paths::root());
+    if Path::new(&filename).exists()
+    {
+        let bytes: [u8; 32] = hex::decode(fs::read_to_string(&filename)?.trim())?
+            .try_into().map_err(|_| format!("{} isn't an Ed25519 key", &filename))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    let key = SigningKey::from_bytes(&bytes);
+    write_private(&filename, &hex::encode(bytes))?;
+    fs::write(format!("{}/device.pub", paths::root()), hex::encode(key.verifying_key().to_bytes()))?;
+    info!("Made a new device key {}", &filename);
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn write_private(filename: &str, contents: &str) -> io::Result<()>
+{
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(filename)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(filename: &str, contents: &str) -> io::Result<()>
+{
+    fs::write(filename, contents)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn chain(count: u64) -> Vec<ManifestEntry>
+    {
+        let mut entries: Vec<ManifestEntry> = Vec::new();
+        for seq in 0..count
+        {
+            let prev = entries.last().map_or(GENESIS, |e| e.hash.as_str());
+            let entry = ManifestEntry::new(seq, &format!("captures/Door{}.mp4", seq), hex::encode([seq as u8; 32]), 100 + seq, prev);
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[test]
+    fn check_chain_accepts_an_unbroken_chain()
+    {
+        assert_eq!(check_chain(&[]), Ok(()));
+        assert_eq!(check_chain(&chain(3)), Ok(()));
+    }
+
+    #[test]
+    fn check_chain_finds_an_altered_entry()
+    {
+        let mut entries = chain(3);
+        entries[1].size += 1;
+        assert_eq!(check_chain(&entries), Err("entry 1 (captures/Door1.mp4) has been altered".to_string()));
+
+        // Rehashing the altered entry breaks the link to the next
+        entries[1].hash = entries[1].chain_hash();
+        assert_eq!(check_chain(&entries), Err("entry 2 doesn't follow on from the one before".to_string()));
+    }
+
+    #[test]
+    fn check_chain_finds_a_removed_entry()
+    {
+        let mut entries = chain(3);
+        entries.remove(1);
+        assert_eq!(check_chain(&entries), Err("entry 1 is numbered 2".to_string()));
+
+        let mut entries = chain(3);
+        entries.remove(0);
+        assert!(check_chain(&entries).is_err());
+    }
+}
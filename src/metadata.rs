@@ -0,0 +1,191 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::continuous::SegmentRange;
+use crate::encryption;
+use crate::recorder::TimedFrame;
+use crate::storage::write_file;
+
+/// Sidecar file written next to each clip describing the event, frame by frame
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventMetadata {
+    pub camera: String,
+    pub event_id: String,
+    pub part: u32,
+    pub video: String,
+    pub start: String,
+    pub end: String,
+    pub pre_roll_seconds: f64,
+    pub zones: BTreeSet<String>,
+    pub first_image: String,
+    pub best_image: Option<String>,
+    pub preview_gif: Option<String>,
+    pub contact_sheet: Option<String>,
+    #[serde(default)]
+    pub segments: Vec<SegmentRange>,    // where the event is in the continuous recording, instead of a video
+    pub frames: Vec<FrameMetadata>,
+}
+
+/// Detections in one frame, frames without any detections are left out
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FrameMetadata {
+    pub frame: u64,          // index of the frame received for the clip
+    pub offset: f64,         // seconds from the start of the clip
+    pub time: String,
+    pub detections: Vec<DetectionMetadata>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectionMetadata {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub label: String,
+    pub score: f32,
+    pub track_id: u32,
+    pub zones: Vec<String>,
+}
+
+impl EventMetadata
+{
+    pub fn new(camera: &str, event_id: &str, part: u32, video: &str, first_image: &str) -> EventMetadata
+    {
+        EventMetadata {
+            camera: camera.to_string(),
+            event_id: event_id.to_string(),
+            part,
+            video: video.to_string(),
+            start: String::new(),
+            end: String::new(),
+            pre_roll_seconds: 0.0,
+            zones: BTreeSet::new(),
+            first_image: first_image.to_string(),
+            best_image: None,
+            preview_gif: None,
+            contact_sheet: None,
+            segments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Record a frame as it's written to the clip. `start` is the timestamp of the clip's first frame.
+    pub fn add_frame(&mut self, index: u64, start: f64, f: &TimedFrame)
+    {
+        if index == 0 { self.start = f.time.to_rfc3339(); }
+        self.end = f.time.to_rfc3339();
+
+        if f.detections.is_empty() { return; }
+
+        let detections = f.detections.iter().map(|det| {
+            self.zones.extend(det.zones.iter().cloned());
+            DetectionMetadata {
+                x: det.rect.x,
+                y: det.rect.y,
+                width: det.rect.width,
+                height: det.rect.height,
+                label: det.label.to_string(),
+                score: det.score,
+                track_id: det.track_id,
+                zones: det.zones.clone(),
+            }
+        }).collect();
+
+        self.frames.push(FrameMetadata {
+            frame: index,
+            offset: (f.pts - start) / 1000.0,
+            time: f.time.to_rfc3339(),
+            detections,
+        });
+    }
+
+    /// Labels of everything detected during the clip
+    pub fn labels(&self) -> BTreeSet<String>
+    {
+        self.frames.iter().flat_map(|f| f.detections.iter().map(|det| det.label.clone())).collect()
+    }
+
+    /// Highest detection score during the clip
+    pub fn max_score(&self) -> f32
+    {
+        self.frames.iter().flat_map(|f| f.detections.iter().map(|det| det.score)).fold(0.0, f32::max)
+    }
+
+    pub fn load(filename: &str) -> Result<EventMetadata, Box<dyn std::error::Error>>
+    {
+        let contents = fs::read_to_string(Path::new(filename))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, filename: &str) -> Result<(), Box<dyn std::error::Error>>
+    {
+        let json = serde_json::to_string_pretty(self)?;
+        write_file(filename, json)?;
+        Ok(())
+    }
+}
+
+/// Sidecar written next to each continuous segment and timelapse file once it's closed, used to find footage by time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SegmentMetadata {
+    pub camera: String,
+    pub video: String,
+    pub start: String,          // time of the first frame
+    pub end: String,
+    pub fps: f64,               // playback rate of the video
+    pub interval: f64,          // stream seconds per video frame, 0 when the frames are listed in frame_times
+    #[serde(default)]
+    pub frame_times: Vec<String>,
+    #[serde(default)]
+    pub gaps: Vec<Gap>,         // stream time skipped rather than filled, e.g. while timelapse was paused for disk space
+}
+
+/// Stream time missing from a paced video just before one of its frames
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Gap {
+    pub frame: u64,
+    pub seconds: f64,
+}
+
+impl SegmentMetadata
+{
+    pub fn new(camera: &str, video: &str, start: &DateTime<Local>, fps: f64, interval: f64) -> SegmentMetadata
+    {
+        SegmentMetadata {
+            camera: camera.to_string(),
+            video: video.to_string(),
+            start: start.to_rfc3339(),
+            end: start.to_rfc3339(),
+            fps,
+            interval,
+            frame_times: Vec::new(),
+            gaps: Vec::new(),
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<SegmentMetadata, Box<dyn std::error::Error>>
+    {
+        let contents = fs::read_to_string(Path::new(filename))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, filename: &str) -> Result<(), Box<dyn std::error::Error>>
+    {
+        let json = serde_json::to_string_pretty(self)?;
+        write_file(filename, json)?;
+        Ok(())
+    }
+}
+
+/// Sidecar filename for a clip, e.g. `Door20230519-101500.json`
+pub fn sidecar_filename(video_filename: &str) -> String
+{
+    let video_filename = encryption::plain_name(video_filename);
+    match video_filename.rfind('.') {
+        Some(i) => format!("{}.json", &video_filename[..i]),
+        None => format!("{}.json", video_filename),
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+use rumqttc::{Client, Connection, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::camera::Camera;
+use crate::encryption;
+use crate::events::{Event, EventSink};
+use crate::status::{self, CameraStatus};
+
+/// Publishes camera state and events to an MQTT broker, with Home Assistant discovery
+#[derive(Debug, Deserialize, Clone)]
+pub struct Mqtt {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_topic")]
+    pub topic: String,                  // topics are <topic>/<camera>/...
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_true")]
+    pub snapshots: bool,                // publish the first and best images to <topic>/<camera>/snapshot
+    #[serde(default = "default_status_secs")]
+    pub status_secs: u64,               // how often the fps is published
+}
+
+fn default_port() -> u16 { 1883 }
+fn default_client_id() -> String { "security_camera".to_string() }
+fn default_topic() -> String { "security_camera".to_string() }
+fn default_discovery_prefix() -> String { "homeassistant".to_string() }
+fn default_true() -> bool { true }
+fn default_status_secs() -> u64 { 10 }
+
+pub struct MqttSink {
+    config: Mqtt,
+    client: Client,
+}
+
+impl MqttSink
+{
+    /// Connect to the broker in the background, it reconnects by itself if the connection drops
+    pub fn new(config: &Mqtt, cameras: &[Camera]) -> MqttSink
+    {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(availability_topic(config), "offline", QoS::AtLeastOnce, true));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) { options.set_credentials(username, password); }
+
+        let (client, connection) = Client::new(options, 100);
+        info!("MQTT: Connecting to {}:{}", &config.host, config.port);
+
+        let names: Vec<String> = cameras.iter().map(|c| c.name.clone()).collect();
+        let (c, conf) = (client.clone(), config.clone());
+        thread::spawn(move || run_connection(&conf, &c, connection, &names));
+        let (c, conf) = (client.clone(), config.clone());
+        thread::spawn(move || publish_status(&conf, &c));
+
+        MqttSink { config: config.clone(), client }
+    }
+
+    fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>)
+    {
+        if let Err(e) = self.client.publish(&topic, QoS::AtLeastOnce, retain, payload) { error!("MQTT: Can't publish {}: {}", &topic, e); }
+    }
+
+    fn publish_snapshot(&self, camera: &str, image: &str)
+    {
+        if !self.config.snapshots || image.is_empty() || encryption::is_encrypted(image) { return; }
+        match fs::read(image) {
+            Ok(data) => { self.publish(camera_topic(&self.config, camera, "snapshot"), true, data); }
+            Err(e) => { warn!("MQTT: Can't read snapshot {}: {}", image, e); }
+        }
+    }
+}
+
+impl EventSink for MqttSink
+{
+    fn name(&self) -> &str { "MQTT" }
+
+    fn handle(&mut self, event: &Event)
+    {
+        let json = serde_json::to_string(event).unwrap_or_default();
+        match event.camera() {
+            Some(camera) => { self.publish(camera_topic(&self.config, camera, "events"), false, json.clone()); }
+            None => { self.publish(format!("{}/events", &self.config.topic), false, json.clone()); }
+        }
+
+        match event {
+            Event::Started { camera, first_image, .. } => { self.publish_snapshot(camera, first_image); }
+            Event::Ended { camera, best_image, .. } => {
+                self.publish(camera_topic(&self.config, camera, "last_event"), true, json);
+                self.publish_snapshot(camera, best_image);
+            }
+            Event::CameraOnline { camera } => { self.publish(camera_topic(&self.config, camera, "online"), true, "ON"); }
+            Event::CameraOffline { camera, .. } => {
+                self.publish(camera_topic(&self.config, camera, "online"), true, "OFF");
+                self.publish(camera_topic(&self.config, camera, "person"), true, "OFF");
+            }
+            _ => {}
+        }
+    }
+}
+
+// Topic names use a lower case slug of the camera name
+fn slug(camera: &str) -> String
+{
+    camera.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+fn camera_topic(config: &Mqtt, camera: &str, name: &str) -> String
+{
+    format!("{}/{}/{}", &config.topic, slug(camera), name)
+}
+
+fn availability_topic(config: &Mqtt) -> String
+{
+    format!("{}/availability", &config.topic)
+}
+
+// Handle the connection: announce ourselves each time it's made and act on arm/disarm commands
+fn run_connection(config: &Mqtt, client: &Client, mut connection: Connection, cameras: &[String])
+{
+    let command_topic = format!("{}/+/armed/set", &config.topic);
+    for notification in connection.iter()
+    {
+        match notification {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT: Connected to {}:{}", &config.host, config.port);
+                let _ = client.publish(availability_topic(config), QoS::AtLeastOnce, true, "online");
+                if let Err(e) = client.subscribe(&command_topic, QoS::AtLeastOnce) { error!("MQTT: Can't subscribe to {}: {}", &command_topic, e); }
+                for camera in cameras
+                {
+                    publish_armed(config, client, camera);
+                    if config.discovery { publish_discovery(config, client, camera); }
+                }
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(message))) => {
+                let payload = String::from_utf8_lossy(&message.payload).trim().to_uppercase();
+                let camera = cameras.iter().find(|c| message.topic == camera_topic(config, c, "armed/set"));
+                match (camera, payload.as_str()) {
+                    (Some(camera), "ON" | "ARM") => { status::set_armed(camera, true); publish_armed(config, client, camera); }
+                    (Some(camera), "OFF" | "DISARM") => { status::set_armed(camera, false); publish_armed(config, client, camera); }
+                    _ => { warn!("MQTT: Ignoring '{}' on {}", &payload, &message.topic); }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("MQTT: {}, reconnecting in 10s", e);
+                thread::sleep(Duration::from_secs(10));
+            }
+        }
+    }
+}
+
+fn publish_armed(config: &Mqtt, client: &Client, camera: &str)
+{
+    let state = if status::armed(camera) { "ON" } else { "OFF" };
+    let _ = client.publish(camera_topic(config, camera, "armed"), QoS::AtLeastOnce, true, state);
+}
+
+// Home Assistant entities for a camera, grouped as one device
+fn publish_discovery(config: &Mqtt, client: &Client, camera: &str)
+{
+    let id = format!("{}_{}", slug(&config.client_id), slug(camera));
+    let device = json!({ "identifiers": [&id], "name": camera, "manufacturer": "security_camera" });
+    let entities = [
+        ("binary_sensor", "person", json!({ "name": "Person", "state_topic": camera_topic(config, camera, "person"), "device_class": "occupancy" })),
+        ("binary_sensor", "online", json!({ "name": "Online", "state_topic": camera_topic(config, camera, "online"), "device_class": "connectivity" })),
+        ("sensor", "count", json!({ "name": "People", "state_topic": camera_topic(config, camera, "count"), "state_class": "measurement" })),
+        ("sensor", "fps", json!({ "name": "FPS", "state_topic": camera_topic(config, camera, "fps"), "unit_of_measurement": "fps", "state_class": "measurement" })),
+        ("sensor", "last_event", json!({ "name": "Last event", "state_topic": camera_topic(config, camera, "last_event"),
+            "value_template": "{{ value_json.end }}", "json_attributes_topic": camera_topic(config, camera, "last_event") })),
+        ("camera", "snapshot", json!({ "name": "Snapshot", "topic": camera_topic(config, camera, "snapshot") })),
+        ("switch", "armed", json!({ "name": "Armed", "state_topic": camera_topic(config, camera, "armed"),
+            "command_topic": camera_topic(config, camera, "armed/set"), "icon": "mdi:shield-home" })),
+    ];
+    for (component, name, mut entity) in entities
+    {
+        entity["unique_id"] = json!(format!("{}_{}", &id, name));
+        entity["device"] = device.clone();
+        entity["availability_topic"] = json!(availability_topic(config));
+        let topic = format!("{}/{}/{}/{}/config", &config.discovery_prefix, component, &id, name);
+        if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, true, entity.to_string()) { error!("MQTT: Can't publish {}: {}", &topic, e); }
+    }
+}
+
+// Publish whether people are in view as soon as it changes, and the fps every status_secs
+fn publish_status(config: &Mqtt, client: &Client)
+{
+    let mut last: BTreeMap<String, CameraStatus> = BTreeMap::new();
+    let mut fps_published = Instant::now();
+    loop
+    {
+        thread::sleep(Duration::from_secs(1));
+        let fps_due = fps_published.elapsed() >= Duration::from_secs(config.status_secs);
+        for (camera, now) in status::all()
+        {
+            let before = last.get(&camera);
+            if before.map(|b| b.people) != Some(now.people)
+            {
+                let person = if now.people > 0 { "ON" } else { "OFF" };
+                let _ = client.publish(camera_topic(config, &camera, "person"), QoS::AtLeastOnce, true, person);
+                let _ = client.publish(camera_topic(config, &camera, "count"), QoS::AtLeastOnce, true, now.people.to_string());
+            }
+            if fps_due { let _ = client.publish(camera_topic(config, &camera, "fps"), QoS::AtLeastOnce, true, format!("{:.1}", now.fps)); }
+            last.insert(camera, now);
+        }
+        if fps_due { fps_published = Instant::now(); }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    // A broker taking one client, acknowledging what it sends and passing on its publishes as (topic, payload, retain)
+    fn broker() -> (u16, Receiver<(String, String, bool)>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            loop
+            {
+                let mut header = [0u8; 1];
+                if stream.read_exact(&mut header).is_err() { return; }
+                let (mut length, mut shift) = (0, 0);
+                loop
+                {
+                    let mut byte = [0u8; 1];
+                    if stream.read_exact(&mut byte).is_err() { return; }
+                    length |= ((byte[0] & 0x7f) as usize) << shift;
+                    shift += 7;
+                    if byte[0] & 0x80 == 0 { break; }
+                }
+                let mut body = vec![0u8; length];
+                if stream.read_exact(&mut body).is_err() { return; }
+
+                let reply = match header[0] >> 4 {
+                    1 => vec![0x20, 2, 0, 0],                   // CONNECT, CONNACK
+                    3 => {                                      // PUBLISH, PUBACK if it's QoS 1
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).to_string();
+                        let qos = (header[0] >> 1) & 3;
+                        let start = 2 + topic_len + if qos > 0 { 2 } else { 0 };
+                        let _ = tx.send((topic, String::from_utf8_lossy(&body[start..]).to_string(), header[0] & 1 == 1));
+                        if qos > 0 { vec![0x40, 2, body[2 + topic_len], body[3 + topic_len]] } else { vec![] }
+                    }
+                    8 => vec![0x90, 3, body[0], body[1], 1],    // SUBSCRIBE, SUBACK
+                    12 => vec![0xd0, 0],                        // PINGREQ, PINGRESP
+                    _ => vec![],
+                };
+                if stream.write_all(&reply).is_err() { return; }
+            }
+        });
+        (port, rx)
+    }
+
+    // The next payload published to a topic and whether it was retained
+    fn next(publishes: &Receiver<(String, String, bool)>, topic: &str) -> (String, bool)
+    {
+        loop
+        {
+            let (t, payload, retain) = publishes.recv_timeout(Duration::from_secs(5)).unwrap_or_else(|_| panic!("nothing published to {}", topic));
+            if t == topic { return (payload, retain); }
+        }
+    }
+
+    fn sink(port: u16) -> MqttSink
+    {
+        let config: Mqtt = serde_json::from_value(json!({ "host": "127.0.0.1", "port": port, "discovery": false })).unwrap();
+        MqttSink::new(&config, &[])
+    }
+
+    #[test]
+    fn slugs_are_lower_case_and_safe()
+    {
+        assert_eq!(slug("Front Door"), "front_door");
+        assert_eq!(slug("Cam/1+#"), "cam_1__");
+    }
+
+    #[test]
+    fn announces_itself_and_publishes_events()
+    {
+        let (port, publishes) = broker();
+        let mut sink = sink(port);
+        assert_eq!(next(&publishes, "security_camera/availability"), ("online".to_string(), true));
+
+        sink.handle(&Event::CameraOnline { camera: "Mqtt Events".to_string() });
+        let (json, retain) = next(&publishes, "security_camera/mqtt_events/events");
+        assert!(!retain);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap()["event"], "camera_online");
+        assert_eq!(next(&publishes, "security_camera/mqtt_events/online"), ("ON".to_string(), true));
+    }
+
+    #[test]
+    fn a_camera_going_offline_sees_no_one()
+    {
+        let (port, publishes) = broker();
+        let mut sink = sink(port);
+        let camera = "Mqtt Offline";
+        status::update(camera, CameraStatus { people: 2, fps: 5.0 });
+        assert_eq!(next(&publishes, "security_camera/mqtt_offline/person"), ("ON".to_string(), true));
+        assert_eq!(next(&publishes, "security_camera/mqtt_offline/count"), ("2".to_string(), true));
+
+        status::offline(camera);
+        sink.handle(&Event::CameraOffline { camera: camera.to_string(), reason: "stream ended".to_string() });
+        assert_eq!(next(&publishes, "security_camera/mqtt_offline/online"), ("OFF".to_string(), true));
+        assert_eq!(next(&publishes, "security_camera/mqtt_offline/person"), ("OFF".to_string(), true));
+        assert_eq!(next(&publishes, "security_camera/mqtt_offline/count"), ("0".to_string(), true));
+    }
+}
@@ -0,0 +1,93 @@
+use std::cmp::max;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+
+use opencv::{prelude::*, Result};
+use opencv::core::{Point, Scalar};
+use opencv::imgproc::{FONT_HERSHEY_SIMPLEX, LINE_8, LINE_AA, put_text, rectangle};
+
+use crate::camera::Detection;
+use crate::encryption;
+use crate::recorder::TimedFrame;
+
+/// Information burned into recorded frames, snapshots and timelapse frames
+#[derive(Debug, Deserialize, Clone)]
+pub struct Overlay {
+    #[serde(default)]
+    pub timestamp: bool,
+    #[serde(default)]
+    pub camera_name: bool,
+    #[serde(default)]
+    pub detections: bool,
+    #[serde(default)]
+    pub keep_original: bool,     // also write the unannotated frames, e.g. for evidence
+}
+
+impl Overlay
+{
+    /// Copy of the frame with the overlay drawn on it
+    pub fn render(&self, camera: &str, f: &TimedFrame) -> Mat
+    {
+        let mut frame = f.frame.clone();
+        if let Err(e) = self.draw(&mut frame, camera, &f.time, &f.detections)
+        {
+            error!("{}: Error drawing overlay: {}", camera, e);
+        }
+        frame
+    }
+
+    fn draw(&self, frame: &mut Mat, camera: &str, time: &DateTime<Local>, detections: &[Detection]) -> Result<()>
+    {
+        // Scale the text with the frame, Hershey simplex is about 22 pixels high at scale 1.0
+        let h = frame.rows();
+        let scale = (h as f64) / 900.0;
+        let thickness = max(1, h / 360);
+        let margin = max(4, h / 60);
+        let line_height = ((22.0 * scale) as i32) + margin;
+
+        let mut y = line_height;
+        if self.timestamp
+        {
+            let text = time.format("%Y-%m-%d %H:%M:%S").to_string();
+            outlined_text(frame, &text, Point::new(margin, y), scale, thickness)?;
+            y += line_height;
+        }
+        if self.camera_name
+        {
+            outlined_text(frame, camera, Point::new(margin, y), scale, thickness)?;
+        }
+
+        if self.detections
+        {
+            let color = Scalar::from((64.0, 240.0, 64.0));
+            for det in detections
+            {
+                rectangle(frame, det.rect, color, thickness + 1, LINE_8, 0)?;
+                let text = format!("{} {:.2}", det.label, det.score);
+                let org = Point::new(det.rect.x, max(line_height, det.rect.y - margin / 2));
+                outlined_text(frame, &text, org, scale * 0.8, thickness)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// White text with a black outline so it's readable on any background
+fn outlined_text(frame: &mut Mat, text: &str, org: Point, scale: f64, thickness: i32) -> Result<()>
+{
+    let black = Scalar::from((0.0, 0.0, 0.0));
+    let white = Scalar::from((255.0, 255.0, 255.0));
+    put_text(frame, text, org, FONT_HERSHEY_SIMPLEX, scale, black, thickness + 2, LINE_AA, false)?;
+    put_text(frame, text, org, FONT_HERSHEY_SIMPLEX, scale, white, thickness, LINE_AA, false)
+}
+
+/// Filename for the unannotated copy of a file, e.g. `Door20230519-101500-original.mp4`
+pub fn original_filename(filename: &str) -> String
+{
+    let plain = encryption::plain_name(filename);
+    let suffix = &filename[plain.len()..];
+    match plain.rfind('.') {
+        Some(i) => format!("{}-original{}{}", &plain[..i], &plain[i..], suffix),
+        None => format!("{}-original{}", plain, suffix),
+    }
+}
@@ -0,0 +1,366 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+
+use crate::encryption;
+use crate::retention::Category;
+use crate::storage::write_error;
+
+/// Templates for each kind of capture. `{root}` is the storage root, `{camera}` the camera name
+/// and `{date}` the date of the capture e.g. 20230519. Directories are created as needed.
+/// The template is the directory for the files, unless its last part names the file with `{name}` (the usual
+/// filename e.g. Door20230519-101500-002), `{event_id}` (e.g. Door20230519-101500) or `{part}` (e.g. -002).
+/// The file's own extension is used whatever the template ends with.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PathTemplates {
+    #[serde(default = "default_people_video")]
+    pub people_video: String,
+    #[serde(default = "default_people_photos")]
+    pub people_photos: String,
+    #[serde(default = "default_timelapse")]
+    pub timelapse: String,
+    #[serde(default = "default_continuous")]
+    pub continuous: String,
+}
+
+fn default_people_video() -> String { "{root}/people/video".to_string() }
+fn default_people_photos() -> String { "{root}/people/photos".to_string() }
+fn default_timelapse() -> String { "{root}/timelapse".to_string() }
+fn default_continuous() -> String { "{root}/continuous".to_string() }
+pub fn default_storage_root() -> String { "captures".to_string() }
+
+impl Default for PathTemplates
+{
+    fn default() -> Self
+    {
+        PathTemplates {
+            people_video: default_people_video(),
+            people_photos: default_people_photos(),
+            timelapse: default_timelapse(),
+            continuous: default_continuous(),
+        }
+    }
+}
+
+struct Layout {
+    root: String,
+    templates: PathTemplates,
+}
+
+static LAYOUT: OnceLock<Layout> = OnceLock::new();
+
+/// Set the storage root and templates, before any cameras start
+pub fn init(root: &str, templates: &PathTemplates)
+{
+    let root = match root.trim_end_matches('/') {
+        "" => "/".to_string(),
+        root => root.to_string(),
+    };
+    if let Some(filename) = split_template(&templates.people_photos).1.filter(|f| !f.contains("{name}"))
+    {
+        warn!("The people_photos filename {} has no {{name}}, an event's photos will overwrite each other.", filename);
+    }
+    if LAYOUT.set(new_layout(root, templates.clone())).is_err() { warn!("Capture paths already set."); }
+}
+
+fn layout() -> &'static Layout
+{
+    LAYOUT.get_or_init(|| new_layout(default_storage_root(), PathTemplates::default()))
+}
+
+fn new_layout(root: String, templates: PathTemplates) -> Layout
+{
+    if let Err(e) = fs::create_dir_all(&root) { error!("Can't create storage root {}: {}", &root, e); }
+    Layout { root, templates }
+}
+
+/// Directory all captures are written under
+pub fn root() -> &'static str
+{
+    &layout().root
+}
+
+fn template(category: Category) -> &'static str
+{
+    let templates = &layout().templates;
+    match category {
+        Category::PeopleVideo => &templates.people_video,
+        Category::PeoplePhotos => &templates.people_photos,
+        Category::Timelapse => &templates.timelapse,
+        Category::Continuous => &templates.continuous,
+    }
+}
+
+// A template split into its directory and, if it names the file, the filename
+fn split_template(template: &str) -> (&str, Option<&str>)
+{
+    match template.rsplit_once('/') {
+        Some((directory, last)) if ["{name}", "{event_id}", "{part}"].iter().any(|p| last.contains(p)) => (directory, Some(last)),
+        _ => (template, None),
+    }
+}
+
+/// Full path for a capture, creating its directory if needed. `name` is the usual filename, it's sanitised so it
+/// stays in the directory and has `.age` added if the capture will be encrypted.
+pub fn file(category: Category, camera: &str, time: &DateTime<Local>, name: &str) -> String
+{
+    let camera = sanitise(camera);
+    let date = time.format("%Y%m%d").to_string();
+    let (directory, filename) = split_template(template(category));
+    let directory = directory
+        .replace("{root}", root())
+        .replace("{camera}", &camera)
+        .replace("{date}", &date);
+
+    if let Err(e) = fs::create_dir_all(&directory) { write_error(&directory, &e); }
+    let name = sanitise(name);
+    let name = match filename {
+        Some(filename) => template_filename(filename, &camera, &date, &name),
+        None => name,
+    };
+    format!("{}/{}", directory, encryption::stored_name(&name))
+}
+
+// Fill in a filename template from the usual name of a file, e.g. `{event_id}{part}.mp4` and `Door20230519-101500-002.mp4`
+fn template_filename(template: &str, camera: &str, date: &str, name: &str) -> String
+{
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (name, ""),
+    };
+    let (event_id, part) = split_event_id(stem, camera);
+
+    // The extension in the template, if it has one, is only there to read well
+    let template = match template.rfind('.') {
+        Some(dot) if !template[dot..].contains('}') => &template[..dot],
+        _ => template,
+    };
+    let mut filled = sanitise(&template
+        .replace("{name}", stem)
+        .replace("{event_id}", event_id)
+        .replace("{part}", part)
+        .replace("{camera}", camera)
+        .replace("{date}", date));
+
+    // The parts of a long event mustn't overwrite each other
+    if !template.contains("{name}") && !template.contains("{part}") { filled += part; }
+    if extension.is_empty() { filled } else { format!("{}.{}", filled, extension) }
+}
+
+// The event id and part number of a file's stem, e.g. `Door20230519-101500-002-first` is Door20230519-101500 and -002.
+// A name that doesn't start with the camera and a timestamp is all event id.
+fn split_event_id<'a>(stem: &'a str, camera: &str) -> (&'a str, &'a str)
+{
+    let id_len = camera.len() + 15;
+    let timestamped = stem.starts_with(camera) && stem.get(camera.len()..id_len).is_some_and(is_timestamp);
+    if !timestamped { return (stem, ""); }
+
+    let rest = &stem[id_len..];
+    let numbered = rest.len() >= 4 && rest.starts_with('-') && rest.as_bytes()[1..4].iter().all(|b| b.is_ascii_digit())
+        && (rest.len() == 4 || rest[4..].starts_with('-'));
+    (&stem[..id_len], if numbered { &rest[..4] } else { "" })
+}
+
+/// Is this a capture's timestamp, e.g. 20230519-101500 ?
+pub fn is_timestamp(text: &str) -> bool
+{
+    text.len() == 15 && NaiveDateTime::parse_from_str(text, "%Y%m%d-%H%M%S").is_ok()
+}
+
+/// Does text start with a timestamp, or a date such as a daily timelapse has e.g. 20230519.mp4 ?
+pub fn starts_with_time(text: &str) -> bool
+{
+    let date = text.get(..8).is_some_and(|d| NaiveDate::parse_from_str(d, "%Y%m%d").is_ok())
+        && !text[8..].starts_with(|c: char| c.is_ascii_digit());
+    date || text.get(..15).is_some_and(is_timestamp)
+}
+
+/// Make a name safe to use as a single path component, e.g. a camera named `Front/Back` becomes `Front_Back`
+pub fn sanitise(name: &str) -> String
+{
+    let s: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ' ' { c } else { '_' })
+        .collect();
+    match s.trim_matches('.').is_empty() {
+        true => s.replace('.', "_"),
+        false => s,
+    }
+}
+
+/// All the files in a category, found by walking the fixed part of its template.
+/// With a filename in the template, files starting as it does are found, so the sidecars and keep markers are too.
+pub fn files(category: Category) -> Vec<PathBuf>
+{
+    let (directory, filename) = split_template(template(category));
+    let pattern = directory.replace("{root}", root());
+    let components: Vec<&str> = pattern.split('/').collect();
+
+    // The walk starts from the components before the first placeholder
+    let fixed = components.iter().take_while(|c| !c.contains('{')).count();
+    let base = components[..fixed].join("/");
+    let base = if base.is_empty() && pattern.starts_with('/') { "/".to_string() } else { base };
+
+    let filename = filename.map(|f| f.split('.').next().unwrap_or_default());
+    let mut files = Vec::new();
+    walk(Path::new(&base), &components[fixed..], filename, &mut files);
+    files
+}
+
+/// The sanitised camera name of a file going by its `{camera}` directory, if the category's template has one
+pub fn camera_directory(category: Category, path: &Path) -> Option<String>
+{
+    let (directory, _) = split_template(template(category));
+    let components: Vec<&str> = directory.split('/').collect();
+    let from_end = components.iter().rev().position(|c| c.contains("{camera}"))?;
+    let name = path.parent()?.ancestors().nth(from_end)?.file_name()?.to_string_lossy().to_string();
+
+    // Text either side of the camera is taken off, other placeholders alongside it can't be
+    let (before, after) = components[components.len() - 1 - from_end].split_once("{camera}")?;
+    if before.contains('{') || after.contains('{') { return None; }
+    name.strip_prefix(before)?.strip_suffix(after).map(|c| c.to_string())
+}
+
+// Collect the files in directories matching the remaining template components, and the filename if there is one
+fn walk(dir: &Path, remaining: &[&str], filename: Option<&str>, files: &mut Vec<PathBuf>)
+{
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten()
+    {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        match remaining.first() {
+            None => {
+                if path.is_file() && filename.is_none_or(|f| matches(f, &name, false)) { files.push(path); }
+            }
+            Some(component) => {
+                if path.is_dir() && matches(component, &name, true) { walk(&path, &remaining[1..], filename, files); }
+            }
+        }
+    }
+}
+
+// Does a name match a template component, or start as it does if not `whole` ? Each placeholder matches any text.
+fn matches(component: &str, name: &str, whole: bool) -> bool
+{
+    let mut parts = Vec::new();
+    let mut rest = component;
+    while let Some(open) = rest.find('{')
+    {
+        parts.push(&rest[..open]);
+        rest = match rest[open..].find('}') {
+            Some(close) => &rest[open + close + 1..],
+            None => "",
+        };
+    }
+    parts.push(rest);
+
+    // Literal parts must appear in order, the first at the start and the last at the end
+    let mut position = 0;
+    for (i, part) in parts.iter().enumerate()
+    {
+        if i == 0
+        {
+            if !name.starts_with(part) { return false; }
+            position = part.len();
+        }
+        else if i == parts.len() - 1 && whole
+        {
+            return name.len() >= position + part.len() && name.ends_with(part);
+        }
+        else
+        {
+            match name[position..].find(part) {
+                Some(p) => position += p + part.len(),
+                None => return false,
+            }
+        }
+    }
+    name.len() == position || !whole
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn sanitise_keeps_names_in_one_directory()
+    {
+        assert_eq!(sanitise("Front Door"), "Front Door");
+        assert_eq!(sanitise("Front/Back"), "Front_Back");
+        assert_eq!(sanitise("../etc"), ".._etc");
+        assert_eq!(sanitise(".."), "__");
+        assert_eq!(sanitise("Door20230519-101500.mp4"), "Door20230519-101500.mp4");
+    }
+
+    #[test]
+    fn split_template_finds_a_filename()
+    {
+        assert_eq!(split_template("{root}/people/video"), ("{root}/people/video", None));
+        assert_eq!(split_template("{root}/{camera}/{date}"), ("{root}/{camera}/{date}", None));
+        assert_eq!(split_template("{root}/{camera}/{event_id}{part}.mp4"), ("{root}/{camera}", Some("{event_id}{part}.mp4")));
+        assert_eq!(split_template("{root}/photos/{date}-{name}"), ("{root}/photos", Some("{date}-{name}")));
+    }
+
+    #[test]
+    fn split_event_id_finds_the_part()
+    {
+        assert_eq!(split_event_id("Door20230519-101500", "Door"), ("Door20230519-101500", ""));
+        assert_eq!(split_event_id("Door20230519-101500-002", "Door"), ("Door20230519-101500", "-002"));
+        assert_eq!(split_event_id("Door20230519-101500-002-first", "Door"), ("Door20230519-101500", "-002"));
+        assert_eq!(split_event_id("Door20230519-101500-first", "Door"), ("Door20230519-101500", ""));
+        assert_eq!(split_event_id("Door20230519", "Door"), ("Door20230519", ""));
+        assert_eq!(split_event_id("Garage20230519-101500", "Door"), ("Garage20230519-101500", ""));
+    }
+
+    #[test]
+    fn template_filename_uses_the_files_extension()
+    {
+        let fill = |template, name| template_filename(template, "Door", "20230519", name);
+        assert_eq!(fill("{event_id}{part}.mp4", "Door20230519-101500-002.mp4"), "Door20230519-101500-002.mp4");
+        assert_eq!(fill("{event_id}{part}.mp4", "Door20230519-101500-002.json"), "Door20230519-101500-002.json");
+        assert_eq!(fill("{date}_{name}", "Door20230519-101500-first.jpg"), "20230519_Door20230519-101500-first.jpg");
+        assert_eq!(fill("clip-{camera}", "Door20230519-101500.mp4"), "clip-Door.mp4");
+    }
+
+    #[test]
+    fn template_filename_keeps_parts_apart()
+    {
+        let fill = |template, name| template_filename(template, "Door", "20230519", name);
+        assert_eq!(fill("{event_id}.mp4", "Door20230519-101500-002.mp4"), "Door20230519-101500-002.mp4");
+        assert_eq!(fill("{event_id}.mp4", "Door20230519-101500.mp4"), "Door20230519-101500.mp4");
+    }
+
+    #[test]
+    fn timestamps_and_dates()
+    {
+        assert!(is_timestamp("20230519-101500"));
+        assert!(!is_timestamp("20230519-251500"));
+        assert!(!is_timestamp("20230519101500"));
+        assert!(starts_with_time("20230519-101500-002.mp4"));
+        assert!(starts_with_time("20230519.mp4"));
+        assert!(starts_with_time("20230519"));
+        assert!(!starts_with_time("202305191.mp4"));
+        assert!(!starts_with_time("20231319.mp4"));
+        assert!(!starts_with_time("2023"));
+    }
+
+    #[test]
+    fn matches_placeholders()
+    {
+        assert!(matches("{camera}", "Door", true));
+        assert!(matches("cam-{camera}", "cam-Door", true));
+        assert!(!matches("cam-{camera}", "Door", true));
+        assert!(matches("{date}-{camera}", "20230519-Door", true));
+        assert!(!matches("{camera}.d", "Door", true));
+        assert!(matches("people", "people", true));
+        assert!(!matches("people", "peoplex", true));
+        assert!(matches("clip-", "clip-Door.mp4", false));
+        assert!(!matches("clip-", "Door.mp4", false));
+    }
+}
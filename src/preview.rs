@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use serde::Deserialize;
+
+use opencv::{prelude::*, Result};
+use opencv::core::{CV_8UC3, Rect, Scalar, Size};
+use opencv::imgproc::{COLOR_BGR2RGB, cvt_color, INTER_AREA, resize};
+
+use crate::recorder::write_image;
+use crate::storage::{finalise, part_filename};
+
+const SHEET_COLUMNS: usize = 4;
+
+/// Small previews of a person recording, for notifications
+#[derive(Debug, Deserialize, Clone)]
+pub struct Preview {
+    #[serde(default = "default_true")]
+    pub gif: bool,                  // animated gif of the highlight around the best frame
+    #[serde(default = "default_gif_seconds")]
+    pub gif_seconds: f64,
+    #[serde(default = "default_gif_fps")]
+    pub gif_fps: f64,
+    #[serde(default = "default_true")]
+    pub contact_sheet: bool,        // jpeg grid of frames evenly spaced through the clip
+    #[serde(default = "default_contact_sheet_frames")]
+    pub contact_sheet_frames: usize,    // 0 for no contact sheet
+    #[serde(default = "default_width")]
+    pub width: i32,                 // width of the gif and each contact sheet frame
+}
+
+fn default_true() -> bool { true }
+fn default_gif_seconds() -> f64 { 4.0 }
+fn default_gif_fps() -> f64 { 5.0 }
+fn default_contact_sheet_frames() -> usize { 12 }
+fn default_width() -> i32 { 320 }
+
+impl Preview
+{
+    /// Check the settings when the config is loaded
+    pub fn check(&self) -> Result<(), String>
+    {
+        if !self.gif_fps.is_finite() || self.gif_fps <= 0.0 { return Err(format!("preview gif_fps should be more than 0, not {}", self.gif_fps)); }
+        if !self.gif_seconds.is_finite() || self.gif_seconds < 0.0 { return Err(format!("preview gif_seconds should be a number of seconds, not {}", self.gif_seconds)); }
+        if self.width <= 0 { return Err(format!("preview width should be more than 0, not {}", self.width)); }
+        Ok(())
+    }
+}
+
+/// Collects downscaled frames as a clip is written.
+/// Only a few seconds either side of the best frame are kept for the gif, and the contact sheet
+/// frames are thinned out as the clip grows so memory use doesn't depend on the clip length.
+pub struct PreviewBuilder {
+    preview: Preview,
+    size: Size,
+    next_sample: f64,
+    recent: VecDeque<Mat>,      // gif frames leading up to now
+    highlight: Vec<Mat>,        // gif frames around the best frame
+    after_best: usize,          // gif frames still wanted after the best frame
+    sheet: Vec<Mat>,
+    sheet_stride: u64,
+    frames: u64,
+}
+
+impl PreviewBuilder
+{
+    pub fn new(preview: &Preview, frame_size: Size) -> PreviewBuilder
+    {
+        let height = if frame_size.width > 0 { frame_size.height * preview.width / frame_size.width } else { 0 };
+        PreviewBuilder {
+            preview: preview.clone(),
+            size: Size::new(preview.width, height & !1),
+            next_sample: 0.0,
+            recent: VecDeque::new(),
+            highlight: Vec::new(),
+            after_best: 0,
+            sheet: Vec::new(),
+            sheet_stride: 1,
+            frames: 0,
+        }
+    }
+
+    fn gif_frames(&self) -> usize
+    {
+        ((self.preview.gif_seconds * self.preview.gif_fps) as usize).max(1)
+    }
+
+    /// The next frame added is the best one, centre the gif on it
+    pub fn best(&mut self)
+    {
+        self.highlight = self.recent.iter().cloned().collect();
+        self.after_best = self.gif_frames() - self.highlight.len();
+        self.next_sample = 0.0;
+    }
+
+    pub fn add_frame(&mut self, frame: &Mat, pts: f64)
+    {
+        if let Err(e) = self.sample(frame, pts) { error!("Error sampling preview frame: {}", e); }
+    }
+
+    fn sample(&mut self, frame: &Mat, pts: f64) -> Result<()>
+    {
+        let gif_sample = self.preview.gif && pts >= self.next_sample;
+        let sheet_sample = self.preview.contact_sheet && self.preview.contact_sheet_frames > 0 && (self.frames % self.sheet_stride == 0);
+        self.frames += 1;
+        if !gif_sample && !sheet_sample { return Ok(()); }
+
+        let mut small = Mat::default();
+        resize(frame, &mut small, self.size, 0.0, 0.0, INTER_AREA)?;
+
+        if sheet_sample
+        {
+            self.sheet.push(small.clone());
+            if self.sheet.len() >= 2 * self.preview.contact_sheet_frames
+            {
+                // Drop every other frame and sample half as often
+                self.sheet = self.sheet.iter().step_by(2).cloned().collect();
+                self.sheet_stride *= 2;
+            }
+        }
+
+        if gif_sample
+        {
+            self.next_sample = pts + 1000.0 / self.preview.gif_fps;
+            if self.after_best > 0
+            {
+                self.highlight.push(small.clone());
+                self.after_best -= 1;
+            }
+            self.recent.push_back(small);
+            if self.recent.len() > self.gif_frames() / 2 { self.recent.pop_front(); }
+        }
+        Ok(())
+    }
+
+    /// Write the animated gif, returns false if there was nothing to write
+    pub fn write_gif(&self, filename: &str) -> Result<bool, Box<dyn std::error::Error>>
+    {
+        // Without a best frame, use the end of the clip
+        let frames: Vec<&Mat> = if self.highlight.is_empty() { self.recent.iter().collect() } else { self.highlight.iter().collect() };
+        if frames.is_empty() { return Ok(false); }
+
+        let width = self.size.width as u16;
+        let height = self.size.height as u16;
+        {
+            let mut encoder = gif::Encoder::new(File::create(part_filename(filename))?, width, height, &[])?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+
+            let mut rgb = Mat::default();
+            for f in frames
+            {
+                cvt_color(f, &mut rgb, COLOR_BGR2RGB, 0)?;
+                let mut frame = gif::Frame::from_rgb_speed(width, height, rgb.data_bytes()?, 10);
+                frame.delay = (100.0 / self.preview.gif_fps) as u16;
+                encoder.write_frame(&frame)?;
+            }
+        }
+        finalise(filename)?;
+        Ok(true)
+    }
+
+    /// Write the contact sheet, returns false if there was nothing to write
+    pub fn write_contact_sheet(&self, filename: &str) -> Result<bool>
+    {
+        if self.sheet.is_empty() { return Ok(false); }
+
+        // Pick evenly spaced frames from those sampled
+        let n = self.preview.contact_sheet_frames.min(self.sheet.len()).max(1);
+        let frames: Vec<&Mat> = (0..n).map(|i| &self.sheet[i * self.sheet.len() / n]).collect();
+
+        let columns = SHEET_COLUMNS.min(n);
+        let rows = n.div_ceil(columns);
+        let (w, h) = (self.size.width, self.size.height);
+        let sheet = Mat::new_rows_cols_with_default(h * rows as i32, w * columns as i32, CV_8UC3, Scalar::all(0.0))?;
+
+        for (i, f) in frames.iter().enumerate()
+        {
+            let r = Rect::new(w * (i % columns) as i32, h * (i / columns) as i32, w, h);
+            let mut cell = Mat::roi(&sheet, r)?;
+            f.copy_to(&mut cell)?;
+        }
+
+        write_image(filename, &sheet)
+    }
+}
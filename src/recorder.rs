@@ -0,0 +1,254 @@
+use std::time::Instant;
+use chrono::{DateTime, Duration, Local};
+
+use opencv::{prelude::*, Result, videoio};
+use opencv::core::{Size, Vector};
+use opencv::imgcodecs::imwrite;
+use opencv::videoio::{VideoCapture, VideoWriter};
+
+use crate::camera::Detection;
+use crate::metadata::Gap;
+use crate::storage::{finalise, part_filename, write_error, Writing};
+
+const DEFAULT_FPS: f64 = 15.0;
+const MAX_FPS: f64 = 60.0;
+const MAX_DRIFT_MS: f64 = 5000.0;  // stream clock is ignored if it wanders this far from the wall clock
+const MAX_FILL_MS: f64 = 10000.0;  // longer gaps, e.g. timelapse paused for disk space, are skipped rather than filled
+
+/// A frame along with the time it was read from the stream
+pub struct TimedFrame {
+    pub frame: Mat,
+    pub pts: f64,               // milliseconds since the stream was opened
+    pub time: DateTime<Local>,  // wall clock time of the frame
+    pub detections: Vec<Detection>,
+}
+
+/// Timestamps frames as they are read.
+/// Uses the stream's own clock (CAP_PROP_POS_MSEC) when it's sane, otherwise the wall clock.
+pub struct StreamClock {
+    opened: DateTime<Local>,
+    started: Instant,
+    stream_base: Option<f64>,
+    last: f64,
+}
+
+impl StreamClock
+{
+    pub fn new() -> StreamClock
+    {
+        StreamClock {
+            opened: Local::now(),
+            started: Instant::now(),
+            stream_base: None,
+            last: 0.0,
+        }
+    }
+
+    /// Timestamp for the frame just read from `cam`, in milliseconds since the stream was opened
+    pub fn stamp(&mut self, cam: &VideoCapture) -> f64
+    {
+        let wall = self.started.elapsed().as_secs_f64() * 1000.0;
+        let pos = cam.get(videoio::CAP_PROP_POS_MSEC).unwrap_or(0.0);
+
+        let mut pts = wall;
+        if pos > 0.0
+        {
+            let base = *self.stream_base.get_or_insert(pos - wall);
+            let stream = pos - base;
+            if (stream >= self.last) && ((stream - wall).abs() < MAX_DRIFT_MS)
+            {
+                pts = stream;
+            } else {
+                // Stream clock jumped (e.g. camera restarted its encoder), re-align with the wall clock
+                self.stream_base = Some(pos - wall);
+            }
+        }
+
+        if pts < self.last { pts = self.last; }
+        self.last = pts;
+        pts
+    }
+
+    /// Wall clock time corresponding to a timestamp from `stamp()`
+    pub fn time(&self, pts: f64) -> DateTime<Local>
+    {
+        self.opened + Duration::milliseconds(pts as i64)
+    }
+}
+
+/// Pick the frame rate for a recording, preferring the rate the stream advertises
+pub fn recording_fps(nominal_fps: f64, measured_fps: f64) -> f64
+{
+    if nominal_fps >= 1.0 && nominal_fps <= MAX_FPS { nominal_fps }
+    else if measured_fps >= 1.0 && measured_fps <= MAX_FPS { measured_fps.round() }
+    else { DEFAULT_FPS }
+}
+
+/// Writes frames to a constant rate video, duplicating or dropping frames as needed
+/// so that the recording plays back in real time whatever rate the camera delivers.
+pub struct PacedWriter {
+    writer: VideoWriter,
+    interval: f64,      // stream milliseconds per output frame, 0 writes every frame as it comes
+    next: Option<f64>,  // timestamp of the next output frame
+    last: Option<Mat>,
+    frames: u64,        // frames written so far
+    gaps: Vec<Gap>,
+    filename: Option<String>,   // moved into place from its part name on release
+    writing: Option<Writing>,
+}
+
+impl PacedWriter
+{
+    pub fn new(writer: VideoWriter, interval: f64) -> PacedWriter
+    {
+        PacedWriter {
+            writer,
+            interval,
+            next: None,
+            last: None,
+            frames: 0,
+            gaps: Vec::new(),
+            filename: None,
+            writing: None,
+        }
+    }
+
+    /// Create a new video file, it's written under its part name until released
+    pub fn create(filename: &str, fps: f64, size: Size, interval: f64) -> Result<PacedWriter>
+    {
+        let part = part_filename(filename);
+        let mut writer = PacedWriter::new(create_video_writer(&part, fps, size)?, interval);
+        writer.filename = Some(filename.to_string());
+        writer.writing = Some(Writing::new(&part));
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, frame: Mat, pts: f64) -> Result<()>
+    {
+        if self.interval <= 0.0 { return self.writer.write(&frame); }
+
+        let mut next = *self.next.get_or_insert(pts);
+
+        // Fill any gap with the previous frame, unless it's so long it's better to note it and carry on from now
+        if pts - next > MAX_FILL_MS.max(2.0 * self.interval)
+        {
+            self.gaps.push(Gap { frame: self.frames, seconds: (pts - next) / 1000.0 });
+            next = pts;
+        }
+        if let Some(last) = &self.last
+        {
+            while next + self.interval <= pts
+            {
+                self.writer.write(last)?;
+                self.frames += 1;
+                next += self.interval;
+            }
+        }
+
+        // Frames arriving faster than the output rate are dropped
+        if next <= pts
+        {
+            self.writer.write(&frame)?;
+            self.frames += 1;
+            next += self.interval;
+        }
+
+        self.next = Some(next);
+        self.last = Some(frame);
+        Ok(())
+    }
+
+    /// Gaps skipped so far, for the file's sidecar
+    pub fn gaps(&self) -> &[Gap]
+    {
+        &self.gaps
+    }
+
+    pub fn release(&mut self) -> Result<()>
+    {
+        let r = self.writer.release();
+        self.writing = None;
+        r?;
+        match self.filename.take() {
+            Some(filename) => finalise(&filename).map_err(|e| opencv::Error::new(-1, format!("Can't finalise {}: {}", filename, e))),
+            None => Ok(()),
+        }
+    }
+
+    /// Close the file but leave it under its part name, for more to be done to it before it's finalised
+    pub fn release_part(&mut self) -> Result<()>
+    {
+        self.filename = None;
+        self.release()
+    }
+}
+
+/// Open a video file, a failure is reported and gives None
+pub fn open_writer(filename: &str, fps: f64, size: Size, interval: f64) -> Option<PacedWriter>
+{
+    match PacedWriter::create(filename, fps, size, interval) {
+        Ok(writer) => Some(writer),
+        Err(e) => {
+            write_error(filename, &e);
+            None
+        }
+    }
+}
+
+/// Write a frame, a writer that fails is reported and dropped so the rest of the file is skipped
+pub fn write_frame(writer: &mut Option<PacedWriter>, frame: Mat, pts: f64, filename: &str)
+{
+    let failed = match writer {
+        Some(w) => w.write(frame, pts).err(),
+        None => None,
+    };
+    if let Some(e) = failed
+    {
+        write_error(filename, &e);
+        *writer = None;
+    }
+}
+
+pub fn close_writer(writer: &mut Option<PacedWriter>, filename: &str)
+{
+    if let Some(w) = writer
+    {
+        if let Err(e) = w.release() { write_error(filename, &e); }
+    }
+    *writer = None;
+}
+
+/// Close a video file leaving it under its part name, returns whether there's a file to finalise
+pub fn close_part(writer: &mut Option<PacedWriter>, filename: &str) -> bool
+{
+    let closed = match writer {
+        Some(w) => match w.release_part() {
+            Ok(_) => true,
+            Err(e) => {
+                write_error(filename, &e);
+                false
+            }
+        },
+        None => false,
+    };
+    *writer = None;
+    closed
+}
+
+/// Write an image under its part name and move it into place, returns false if opencv couldn't encode it
+pub fn write_image(filename: &str, image: &Mat) -> Result<bool>
+{
+    let flags = Vector::new();
+    if !imwrite(&part_filename(filename), image, &flags)? { return Ok(false); }
+    finalise(filename).map_err(|e| opencv::Error::new(-1, format!("Can't finalise {}: {}", filename, e)))?;
+    Ok(true)
+}
+
+fn create_video_writer(filename: &str, fps: f64, size: Size) -> Result<VideoWriter>
+{
+    let fourcc = VideoWriter::fourcc('m' as i8, 'p' as i8, '4' as i8, 'v' as i8)?;
+    let writer = VideoWriter::new(&filename, fourcc, fps, size, true)?;
+    if !writer.is_opened()? { return Err(opencv::Error::new(-1, format!("Can't open video writer for {}", filename))); }
+    info!("Creating new video file: {}", filename);
+    Ok(writer)
+}
@@ -0,0 +1,141 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use opencv::{prelude::*, videoio};
+use opencv::imgcodecs::{imread, IMREAD_COLOR};
+use opencv::videoio::VideoCapture;
+
+use crate::encryption;
+use crate::events::StorageEvent;
+use crate::index;
+use crate::paths;
+use crate::recorder::{PacedWriter, recording_fps};
+use crate::retention::Category;
+use crate::storage::{final_filename, finalise, is_part, part_filename, report};
+
+/// Deal with the part files left behind by a crash or power cut, before any cameras start.
+/// Each is moved to `{root}/quarantine` and then, if what was written can still be read, put back in place
+/// under its final name. Both outcomes are reported as storage events.
+pub fn recover()
+{
+    let quarantine = format!("{}/quarantine", paths::root());
+    let mut recovered = 0;
+
+    for category in Category::all()
+    {
+        for path in paths::files(category)
+        {
+            if !is_part(&path) { continue; }
+            let part = path.to_string_lossy().to_string();
+            let filename = encryption::stored_name(&final_filename(&part));
+
+            if let Err(e) = fs::create_dir_all(&quarantine)
+            {
+                error!("Can't create {}: {}", &quarantine, e);
+                return;
+            }
+            let quarantined = format!("{}/{}", &quarantine, path.file_name().unwrap_or_default().to_string_lossy());
+            if let Err(e) = fs::rename(&part, &quarantined)
+            {
+                error!("Can't move {} to {}: {}", &part, &quarantined, e);
+                continue;
+            }
+
+            if !Path::new(&filename).exists() && recover_file(&quarantined, &filename)
+            {
+                let _ = fs::remove_file(&quarantined);
+                report(StorageEvent::PartRecovered, &filename);
+                recovered += 1;
+            }
+            else
+            {
+                report(StorageEvent::PartQuarantined, &encrypt_quarantined(&quarantined));
+            }
+        }
+    }
+
+    // Recovered sidecars aren't in the index yet
+    if recovered > 0
+    {
+        match index::rebuild() {
+            Ok((events, files)) => info!("Recovered {} files, index rebuilt with {} events and {} files.", recovered, events, files),
+            Err(e) => error!("Can't rebuild the index after recovering files: {}", e),
+        }
+    }
+}
+
+// Put what can be read of a partly written file in place, returns false if there was nothing usable
+fn recover_file(quarantined: &str, filename: &str) -> bool
+{
+    let extension = Path::new(encryption::plain_name(filename)).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let readable = match extension.as_str() {
+        "mp4" => { return recover_video(quarantined, filename).unwrap_or_else(|e| { error!("Can't recover {}: {}", filename, e); false }); }
+        "jpg" | "jpeg" | "png" => imread(quarantined, IMREAD_COLOR).is_ok_and(|m| !m.empty()),
+        "json" => fs::read_to_string(quarantined).ok().and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok()).is_some(),
+        "gif" => readable_gif(quarantined),
+        _ => false,
+    };
+    if !readable { return false; }
+
+    // Copied rather than moved back, so it's complete before it appears under its final name
+    match fs::copy(quarantined, part_filename(filename)).and_then(|_| finalise(filename)) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Can't recover {}: {}", filename, e);
+            false
+        }
+    }
+}
+
+// A video cut off before it was closed has no index, so the frames that can be read are written to a new file
+fn recover_video(quarantined: &str, filename: &str) -> opencv::Result<bool>
+{
+    let mut cam = VideoCapture::from_file(quarantined, videoio::CAP_ANY)?;
+    if !cam.is_opened()? { return Ok(false); }
+    let fps = recording_fps(cam.get(videoio::CAP_PROP_FPS)?, 0.0);
+
+    let mut writer: Option<PacedWriter> = None;
+    loop
+    {
+        let mut frame = Mat::default();
+        if !cam.read(&mut frame)? || frame.size()?.width == 0 { break; }
+        if writer.is_none() { writer = Some(PacedWriter::create(filename, fps, frame.size()?, 0.0)?); }
+        if let Some(w) = &mut writer { w.write(frame, 0.0)?; }
+    }
+
+    match writer {
+        Some(mut w) => { w.release()?; Ok(true) }
+        None => Ok(false),
+    }
+}
+
+// With encryption on, footage mustn't be left readable in quarantine either. Returns the name it's left under.
+fn encrypt_quarantined(quarantined: &str) -> String
+{
+    let encrypted = encryption::stored_name(quarantined);
+    if encrypted == quarantined { return encrypted; }
+
+    match encryption::encrypt_file(quarantined, &encrypted) {
+        Ok(_) => {
+            let _ = fs::remove_file(quarantined);
+            encrypted
+        }
+        Err(e) => {
+            error!("Can't encrypt {}: {}", quarantined, e);
+            quarantined.to_string()
+        }
+    }
+}
+
+fn readable_gif(filename: &str) -> bool
+{
+    let decoder = File::open(filename).ok().and_then(|f| gif::DecodeOptions::new().read_info(f).ok());
+    match decoder {
+        Some(mut decoder) => {
+            let mut frames = 0;
+            while let Ok(Some(_)) = decoder.read_next_frame() { frames += 1; }
+            frames > 0
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,333 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use serde::Deserialize;
+
+use crate::camera::Camera;
+use crate::encryption;
+use crate::index;
+use crate::paths::{self, sanitise};
+use crate::storage::{is_part, is_writing};
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(600);
+const KEEP_EXTENSION: &str = "keep";
+
+/// Limits for one category of a camera's files, either can be left out
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Quota {
+    pub max_age_days: Option<f64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// How much of a camera's footage is kept
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Retention {
+    #[serde(default)]
+    pub people_video: Quota,
+    #[serde(default)]
+    pub people_photos: Quota,
+    #[serde(default)]
+    pub timelapse: Quota,
+    #[serde(default)]
+    pub continuous: Quota,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    PeopleVideo,
+    PeoplePhotos,
+    Timelapse,
+    Continuous,
+}
+
+impl Category
+{
+    pub fn all() -> [Category; 4]
+    {
+        [Category::PeopleVideo, Category::PeoplePhotos, Category::Timelapse, Category::Continuous]
+    }
+}
+
+impl Retention
+{
+    fn quota(&self, category: Category) -> &Quota
+    {
+        match category {
+            Category::PeopleVideo => &self.people_video,
+            Category::PeoplePhotos => &self.people_photos,
+            Category::Timelapse => &self.timelapse,
+            Category::Continuous => &self.continuous,
+        }
+    }
+
+    /// Check the quotas when the config is loaded
+    pub fn check(&self) -> Result<(), String>
+    {
+        for category in Category::all()
+        {
+            if let Some(days) = self.quota(category).max_age_days
+            {
+                if Duration::try_from_secs_f64(days * 86400.0).is_err()
+                {
+                    return Err(format!("max_age_days should be a number of days, not {}", days));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct StoredFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Start the background task that enforces each camera's retention settings
+pub fn start(cameras: Vec<Camera>)
+{
+    if cameras.iter().all(|c| c.retention.is_none()) { return; }
+
+    info!("Retention manager started.");
+    thread::spawn(move || {
+        loop {
+            for camera in &cameras
+            {
+                if let Some(retention) = &camera.retention { enforce(&camera.name, retention); }
+            }
+            thread::sleep(RETENTION_INTERVAL);
+        }
+    });
+}
+
+/// Delete a camera's oldest files until it's within its quotas, returns the number of bytes freed
+pub fn enforce(camera: &str, retention: &Retention) -> u64
+{
+    let protected = protected_prefixes();
+    let mut freed = 0;
+
+    for category in Category::all()
+    {
+        let quota = retention.quota(category);
+        if quota.max_age_days.is_none() && quota.max_bytes.is_none() { continue; }
+
+        let mut files = camera_files(camera, category, &protected);
+
+        if let Some(days) = quota.max_age_days
+        {
+            // Ages too great to subtract from now keep everything
+            let cutoff = Duration::try_from_secs_f64(days * 86400.0).ok().and_then(|age| SystemTime::now().checked_sub(age));
+            while cutoff.is_some_and(|cutoff| files.first().is_some_and(|f| f.modified < cutoff))
+            {
+                let f = files.remove(0);
+                freed += remove(camera, &f, "older than max_age_days");
+            }
+        }
+
+        if let Some(max_bytes) = quota.max_bytes
+        {
+            let mut total: u64 = files.iter().map(|f| f.size).sum();
+            while total > max_bytes && !files.is_empty()
+            {
+                let f = files.remove(0);
+                total -= f.size;
+                freed += remove(camera, &f, "over max_bytes");
+            }
+        }
+    }
+    freed
+}
+
+/// Delete the oldest files of all the cameras, whatever their quotas, until `bytes` have been freed.
+/// Returns the number of bytes freed.
+pub fn emergency(cameras: &[String], bytes: u64) -> u64
+{
+    let protected = protected_prefixes();
+    let mut files = Vec::new();
+    for camera in cameras
+    {
+        for category in Category::all()
+        {
+            files.extend(camera_files(camera, category, &protected).into_iter().map(|f| (camera, f)));
+        }
+    }
+    files.sort_by_key(|(_, f)| f.modified);
+
+    let mut freed = 0;
+    for (camera, f) in files
+    {
+        if freed >= bytes { break; }
+        freed += remove(camera, &f, "emergency, disk nearly full");
+    }
+    if freed > 0 { warn!("Emergency retention freed {} bytes.", freed); }
+    freed
+}
+
+/// Files belonging to a camera that retention may delete, oldest first.
+/// Files still being written, or left partly written, and those of events marked keep are left out.
+pub fn camera_files(camera: &str, category: Category, protected: &[String]) -> Vec<StoredFile>
+{
+    let mut files = Vec::new();
+    let camera = sanitise(camera);
+
+    for path in paths::files(category)
+    {
+        let name = file_name(&path);
+        let belongs = match paths::camera_directory(category, &path) {
+            Some(directory) => directory == camera,
+            None => belongs_to(&name, &camera),
+        };
+        if !belongs || is_keep_marker(&path) || is_writing(&path) || is_part(&path) { continue; }
+        if protected.iter().any(|p| name.starts_with(p.as_str())) { continue; }
+
+        if let Ok(metadata) = fs::metadata(&path)
+        {
+            files.push(StoredFile {
+                path,
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::now()),
+            });
+        }
+    }
+
+    files.sort_by_key(|f| f.modified);
+    files
+}
+
+pub fn remove(camera: &str, f: &StoredFile, reason: &str) -> u64
+{
+    match fs::remove_file(&f.path) {
+        Ok(_) => {
+            info!("{}: Retention removed {} ({} bytes, {})", camera, f.path.display(), f.size, reason);
+            index::forget(&f.path);
+            // Dated directories are removed once they're empty, this fails harmlessly if they aren't
+            if let Some(parent) = f.path.parent() { let _ = fs::remove_dir(parent); }
+            f.size
+        }
+        Err(e) => {
+            error!("{}: Retention can't remove {}: {}", camera, f.path.display(), e);
+            0
+        }
+    }
+}
+
+// Files are named with the camera name followed by a timestamp, e.g. Door20230519-101500.mp4, perhaps after some
+// text from a filename template. The whole timestamp is matched so a camera's name can be the start of another's,
+// e.g. Cam and Cam1, and the name mustn't carry on from a letter or digit before it.
+fn belongs_to(name: &str, camera: &str) -> bool
+{
+    name.match_indices(camera).any(|(i, _)| {
+        !name[..i].ends_with(|c: char| c.is_alphanumeric()) && paths::starts_with_time(&name[i + camera.len()..])
+    })
+}
+
+fn file_name(path: &Path) -> String
+{
+    path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn is_keep_marker(path: &Path) -> bool
+{
+    path.extension().is_some_and(|e| e == KEEP_EXTENSION)
+}
+
+/// Filename prefixes of the files of events marked keep.
+/// An event is kept by creating `<event-id>.keep` in any of the capture directories, this protects
+/// every file starting with the event id plus the photos listed in the event's sidecars.
+pub fn protected_prefixes() -> Vec<String>
+{
+    let mut prefixes = Vec::new();
+    for category in Category::all()
+    {
+        for path in paths::files(category)
+        {
+            if !is_keep_marker(&path) { continue; }
+            if let Some(event_id) = path.file_stem()
+            {
+                prefixes.extend(event_prefixes(&event_id.to_string_lossy()));
+            }
+        }
+    }
+    prefixes
+}
+
+/// Filename prefixes of an event's files, its id plus those named in its sidecars
+pub fn event_prefixes(event_id: &str) -> Vec<String>
+{
+    let mut prefixes = sidecar_prefixes(event_id);
+    prefixes.push(event_id.to_string());
+    prefixes
+}
+
+// Photos and continuous segments named in an event's sidecars, e.g. the best image which is named by its own timestamp
+fn sidecar_prefixes(event_id: &str) -> Vec<String>
+{
+    let mut prefixes = Vec::new();
+    for path in paths::files(Category::PeopleVideo)
+    {
+        let name = file_name(&path);
+        if !name.starts_with(event_id) || path.extension().is_none_or(|e| e != "json") { continue; }
+
+        let sidecar: serde_json::Value = match fs::read_to_string(&path).ok().and_then(|s| serde_json::from_str(&s).ok()) {
+            Some(v) => v,
+            None => continue,
+        };
+        for key in ["first_image", "best_image", "preview_gif", "contact_sheet"]
+        {
+            if let Some(stem) = sidecar[key].as_str().and_then(|f| Path::new(encryption::plain_name(f)).file_stem())
+            {
+                prefixes.push(stem.to_string_lossy().to_string());
+            }
+        }
+        if let Some(segments) = sidecar["segments"].as_array()
+        {
+            for stem in segments.iter().filter_map(|s| s["video"].as_str()).filter_map(|f| Path::new(encryption::plain_name(f)).file_stem())
+            {
+                prefixes.push(stem.to_string_lossy().to_string());
+            }
+        }
+    }
+    prefixes
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn belongs_to_needs_the_timestamp_after_the_camera()
+    {
+        assert!(belongs_to("Cam20230519-101500.mp4", "Cam"));
+        assert!(belongs_to("Cam20230519-101500-002.mp4", "Cam"));
+        assert!(belongs_to("Cam20230519-101500.json", "Cam"));
+        assert!(belongs_to("Cam20230519.mp4", "Cam"));
+        assert!(belongs_to("event_Cam20230519-101500.mp4", "Cam"));
+        assert!(belongs_to("Front Door20230519-101500.mp4", "Front Door"));
+    }
+
+    #[test]
+    fn belongs_to_keeps_similar_names_apart()
+    {
+        assert!(!belongs_to("Cam120230519-101500.mp4", "Cam"));
+        assert!(belongs_to("Cam120230519-101500.mp4", "Cam1"));
+        assert!(!belongs_to("Cam20230519-101500.mp4", "Cam1"));
+        assert!(!belongs_to("MyCam20230519-101500.mp4", "Cam"));
+        assert!(!belongs_to("Cam-notes.txt", "Cam"));
+        assert!(!belongs_to("Door20230519-101500.mp4", "Cam"));
+    }
+
+    #[test]
+    fn check_rejects_ages_that_arent_days()
+    {
+        let retention = |days: &str| -> Retention {
+            serde_json::from_str(&format!(r#"{{"timelapse": {{"max_age_days": {}}}}}"#, days)).unwrap()
+        };
+        assert!(retention("7").check().is_ok());
+        assert!(retention("0.5").check().is_ok());
+        assert!(retention("-1").check().is_err());
+        assert!(retention("1e300").check().is_err());
+        assert!(Retention::default().check().is_ok());
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+/// What a camera is seeing now, updated every second while it's running
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraStatus {
+    pub people: usize,      // in the zones, in the last frame
+    pub fps: f64,
+}
+
+static STATUS: Mutex<BTreeMap<String, CameraStatus>> = Mutex::new(BTreeMap::new());
+static DISARMED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+pub fn update(camera: &str, status: CameraStatus)
+{
+    STATUS.lock().unwrap().insert(camera.to_string(), status);
+}
+
+/// A camera that's gone offline sees no one, until it's running again
+pub fn offline(camera: &str)
+{
+    update(camera, CameraStatus::default());
+}
+
+/// Every running camera's status
+pub fn all() -> BTreeMap<String, CameraStatus>
+{
+    STATUS.lock().unwrap().clone()
+}
+
+/// Cameras are armed when they start. A disarmed camera carries on with timelapse and continuous recording
+/// but people don't start events.
+pub fn armed(camera: &str) -> bool
+{
+    !DISARMED.lock().unwrap().contains(camera)
+}
+
+pub fn set_armed(camera: &str, armed: bool)
+{
+    let mut disarmed = DISARMED.lock().unwrap();
+    let changed = if armed { disarmed.remove(camera) } else { disarmed.insert(camera.to_string()) };
+    if changed { info!("{}: {}", camera, if armed { "Armed" } else { "Disarmed" }); }
+}
@@ -0,0 +1,300 @@
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use std::time::Duration;
+use serde::Deserialize;
+
+use crate::encryption;
+use crate::events::{self, Event, StorageEvent};
+use crate::manifest;
+use crate::paths;
+use crate::retention;
+
+const MONITOR_INTERVAL: Duration = Duration::from_secs(30);
+const MB: u64 = 1024 * 1024;
+const PART: &str = ".part";
+
+/// Free space watermarks for the storage root, in MB and each no higher than the one before
+#[derive(Debug, Deserialize, Clone)]
+pub struct StorageConfig {
+    #[serde(default = "default_emergency_mb")]
+    pub emergency_mb: u64,          // below this, emergency retention runs and storage low is reported
+    #[serde(default = "default_no_timelapse_mb")]
+    pub no_timelapse_mb: u64,       // below this timelapse stops
+    #[serde(default = "default_snapshots_only_mb")]
+    pub snapshots_only_mb: u64,     // below this events are recorded as snapshots only
+}
+
+fn default_emergency_mb() -> u64 { 2048 }
+fn default_no_timelapse_mb() -> u64 { 512 }
+fn default_snapshots_only_mb() -> u64 { 256 }
+
+impl Default for StorageConfig
+{
+    fn default() -> Self
+    {
+        StorageConfig {
+            emergency_mb: default_emergency_mb(),
+            no_timelapse_mb: default_no_timelapse_mb(),
+            snapshots_only_mb: default_snapshots_only_mb(),
+        }
+    }
+}
+
+/// How recording is degraded as the disk fills
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum StorageLevel {
+    Ok,
+    Low,
+    NoTimelapse,
+    SnapshotsOnly,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(StorageLevel::Ok as u8);
+
+pub fn level() -> StorageLevel
+{
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => StorageLevel::Ok,
+        1 => StorageLevel::Low,
+        2 => StorageLevel::NoTimelapse,
+        _ => StorageLevel::SnapshotsOnly,
+    }
+}
+
+impl StorageConfig
+{
+    /// Check the settings when the config is loaded
+    pub fn check(&self) -> Result<(), String>
+    {
+        if self.snapshots_only_mb > self.no_timelapse_mb || self.no_timelapse_mb > self.emergency_mb
+        {
+            return Err(format!("storage watermarks should be snapshots_only_mb ({}) <= no_timelapse_mb ({}) <= emergency_mb ({})",
+                               self.snapshots_only_mb, self.no_timelapse_mb, self.emergency_mb));
+        }
+        Ok(())
+    }
+
+    fn level(&self, free_mb: u64) -> StorageLevel
+    {
+        if free_mb < self.snapshots_only_mb { StorageLevel::SnapshotsOnly }
+        else if free_mb < self.no_timelapse_mb { StorageLevel::NoTimelapse }
+        else if free_mb < self.emergency_mb { StorageLevel::Low }
+        else { StorageLevel::Ok }
+    }
+}
+
+/// Start the background task checking the free space against the watermarks
+pub fn start_monitor(config: StorageConfig, cameras: Vec<String>)
+{
+    thread::spawn(move || {
+        loop {
+            match fs2::available_space(paths::root())
+            {
+                Ok(free) => {
+                    let mut free_mb = free / MB;
+                    if free_mb < config.emergency_mb
+                    {
+                        if level() == StorageLevel::Ok
+                        {
+                            report(StorageEvent::StorageLow, &format!("{} MB free in {}", free_mb, paths::root()));
+                        }
+                        let freed = retention::emergency(&cameras, (config.emergency_mb - free_mb) * MB);
+                        if freed > 0
+                        {
+                            free_mb = fs2::available_space(paths::root()).map(|f| f / MB).unwrap_or(free_mb);
+                        }
+                    }
+
+                    let new_level = config.level(free_mb);
+                    if new_level != level()
+                    {
+                        match new_level {
+                            StorageLevel::Ok => info!("Storage: {} MB free, recording normally.", free_mb),
+                            StorageLevel::Low => warn!("Storage: {} MB free, below emergency_mb.", free_mb),
+                            StorageLevel::NoTimelapse => warn!("Storage: {} MB free, timelapse recording stopped.", free_mb),
+                            StorageLevel::SnapshotsOnly => error!("Storage: {} MB free, events recorded as snapshots only.", free_mb),
+                        }
+                        LEVEL.store(new_level as u8, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => { error!("Can't read free space of {}: {}", paths::root(), e); }
+            }
+            thread::sleep(MONITOR_INTERVAL);
+        }
+    });
+}
+
+/// Report a storage event to the event sinks
+pub fn report(kind: StorageEvent, detail: &str)
+{
+    warn!("Storage event {}: {}", kind.name(), detail);
+    events::publish(Event::Storage { kind, detail: detail.to_string() });
+}
+
+pub fn write_error(filename: &str, e: &dyn Display)
+{
+    error!("Can't write {}: {}", filename, e);
+    report(StorageEvent::WriteError, &format!("{}: {}", filename, e));
+}
+
+/// Name a file is written under until it's complete, e.g. `Door20230519-101500.part.mp4`.
+/// The extension is kept as opencv picks the format from it, files to be encrypted are written in the clear until then.
+pub fn part_filename(filename: &str) -> String
+{
+    let filename = encryption::plain_name(filename);
+    let name_start = filename.rfind('/').map_or(0, |i| i + 1);
+    match filename[name_start..].rfind('.') {
+        Some(i) if i > 0 => format!("{}{}{}", &filename[..name_start + i], PART, &filename[name_start + i..]),
+        _ => format!("{}{}", filename, PART),
+    }
+}
+
+/// Name a part file will have once it's complete
+pub fn final_filename(part: &str) -> String
+{
+    let name_start = part.rfind('/').map_or(0, |i| i + 1);
+    match part[name_start..].rfind(PART) {
+        Some(i) => format!("{}{}", &part[..name_start + i], &part[name_start + i + PART.len()..]),
+        None => part.to_string(),
+    }
+}
+
+/// Is this a file still being written, or left partly written by a crash ?
+pub fn is_part(path: &Path) -> bool
+{
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(PART) || name.contains(&format!("{}.", PART))
+}
+
+/// Move a finished file from its part name into place. It's flushed to disk first so that anything watching
+/// the directory never sees it partly written. Files named `.age` are encrypted on the way, and the file is added to the manifest.
+pub fn finalise(filename: &str) -> io::Result<()>
+{
+    let part = part_filename(filename);
+    match encryption::is_encrypted(filename)
+    {
+        true => {
+            let encrypted = format!("{}{}", &part, encryption::EXTENSION);
+            encryption::encrypt_file(&part, &encrypted)?;
+            fs::rename(&encrypted, filename)?;
+            fs::remove_file(&part)?;
+        }
+        false => {
+            File::open(&part)?.sync_all()?;
+            fs::rename(&part, filename)?;
+        }
+    }
+
+    // The rename only survives a power cut once the directory is synced too
+    let directory = match Path::new(filename).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if let Ok(d) = File::open(directory) { let _ = d.sync_all(); }
+    manifest::record(filename);
+    Ok(())
+}
+
+/// Write a whole file under its part name and then move it into place
+pub fn write_file(filename: &str, contents: impl AsRef<[u8]>) -> io::Result<()>
+{
+    fs::write(part_filename(filename), contents)?;
+    finalise(filename)
+}
+
+// Names of the files currently being written
+static WRITING: Mutex<Vec<OsString>> = Mutex::new(Vec::new());
+
+/// Marks a file as being written until dropped, so that retention leaves it alone
+pub struct Writing {
+    name: OsString,
+}
+
+impl Writing
+{
+    pub fn new(filename: &str) -> Writing
+    {
+        let name = Path::new(filename).file_name().unwrap_or_default().to_os_string();
+        WRITING.lock().unwrap().push(name.clone());
+        Writing { name }
+    }
+}
+
+impl Drop for Writing
+{
+    fn drop(&mut self)
+    {
+        let mut writing = WRITING.lock().unwrap();
+        if let Some(i) = writing.iter().position(|n| n == &self.name) { writing.remove(i); }
+    }
+}
+
+/// Is the file still being written ?
+pub fn is_writing(path: &Path) -> bool
+{
+    match path.file_name() {
+        Some(name) => WRITING.lock().unwrap().iter().any(|n| n == name),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn part_names_go_before_the_extension()
+    {
+        assert_eq!(part_filename("captures/Door20230519-101500.mp4"), "captures/Door20230519-101500.part.mp4");
+        assert_eq!(part_filename("captures/Door20230519-101500.mp4.age"), "captures/Door20230519-101500.part.mp4");
+        assert_eq!(part_filename("captures/v1.2/Door"), "captures/v1.2/Door.part");
+        assert_eq!(part_filename("captures/.hidden"), "captures/.hidden.part");
+    }
+
+    #[test]
+    fn final_names_undo_part_names()
+    {
+        for name in ["captures/Door20230519-101500.mp4", "captures/v1.2/Door", "captures/.hidden", "Door.jpg"]
+        {
+            assert_eq!(final_filename(&part_filename(name)), name);
+        }
+        assert_eq!(final_filename("captures/Door.mp4"), "captures/Door.mp4");
+    }
+
+    #[test]
+    fn is_part_finds_part_files()
+    {
+        assert!(is_part(Path::new("captures/Door20230519-101500.part.mp4")));
+        assert!(is_part(Path::new("captures/Door.part")));
+        assert!(!is_part(Path::new("captures/Door20230519-101500.mp4")));
+        assert!(!is_part(Path::new("captures/party.mp4")));
+    }
+
+    #[test]
+    fn levels_follow_the_watermarks()
+    {
+        let config = StorageConfig::default();
+        assert_eq!(config.level(10000), StorageLevel::Ok);
+        assert_eq!(config.level(2047), StorageLevel::Low);
+        assert_eq!(config.level(511), StorageLevel::NoTimelapse);
+        assert_eq!(config.level(255), StorageLevel::SnapshotsOnly);
+        assert_eq!(config.level(0), StorageLevel::SnapshotsOnly);
+    }
+
+    #[test]
+    fn check_rejects_watermarks_out_of_order()
+    {
+        assert!(StorageConfig::default().check().is_ok());
+        let storage = |emergency_mb, no_timelapse_mb, snapshots_only_mb| StorageConfig { emergency_mb, no_timelapse_mb, snapshots_only_mb };
+        assert!(storage(1000, 1000, 0).check().is_ok());
+        assert!(storage(512, 2048, 256).check().is_err());
+        assert!(storage(2048, 256, 512).check().is_err());
+    }
+}
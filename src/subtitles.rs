@@ -0,0 +1,190 @@
+use std::fs;
+use std::process::Command;
+use serde::Deserialize;
+
+use crate::camera::Detection;
+use crate::encryption;
+use crate::storage::{finalise, part_filename, write_error};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    #[default]
+    Vtt,
+    Srt,
+}
+
+/// Captions of the detections written alongside each person recording
+#[derive(Debug, Deserialize, Clone)]
+pub struct Subtitles {
+    #[serde(default)]
+    pub format: SubtitleFormat,
+    #[serde(default)]
+    pub mux: bool,          // also add the captions to the mp4 as a subtitle stream (needs ffmpeg)
+}
+
+// What's being shown, cues continue while this stays the same
+#[derive(PartialEq)]
+struct Subject {
+    label: String,
+    zones: Vec<String>,
+}
+
+struct Cue {
+    start: f64,
+    end: f64,
+    subjects: Vec<Subject>,
+    scores: Vec<f32>,       // highest score of each subject during the cue
+}
+
+impl Cue
+{
+    fn text(&self) -> String
+    {
+        self.subjects.iter().zip(self.scores.iter()).map(|(s, score)| {
+            let mut label = s.label.clone();
+            if let Some(c) = label.get_mut(0..1) { c.make_ascii_uppercase(); }
+            match s.zones.len() {
+                0 => format!("{} {:.2}", label, score),
+                1 => format!("{} {:.2} in zone {}", label, score, s.zones[0]),
+                _ => format!("{} {:.2} in zones {}", label, score, s.zones.join(", ")),
+            }
+        }).collect::<Vec<String>>().join("\n")
+    }
+}
+
+/// Builds caption cues from the detections in each frame of a clip
+pub struct SubtitleTrack {
+    cues: Vec<Cue>,
+    current: Option<Cue>,
+}
+
+impl SubtitleTrack
+{
+    pub fn new() -> SubtitleTrack
+    {
+        SubtitleTrack {
+            cues: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Add a frame's detections at `offset` seconds into the clip
+    pub fn add_frame(&mut self, offset: f64, detections: &[Detection])
+    {
+        let subjects: Vec<Subject> = detections.iter().map(|det| Subject {
+            label: det.label.to_string(),
+            zones: det.zones.clone(),
+        }).collect();
+
+        if let Some(cue) = &mut self.current
+        {
+            if cue.subjects == subjects
+            {
+                for (score, det) in cue.scores.iter_mut().zip(detections) { *score = score.max(det.score); }
+                return;
+            }
+        }
+
+        self.finish(offset);
+        if !subjects.is_empty()
+        {
+            self.current = Some(Cue {
+                start: offset,
+                end: offset,
+                subjects,
+                scores: detections.iter().map(|det| det.score).collect(),
+            });
+        }
+    }
+
+    /// End the current cue at `offset` seconds
+    pub fn finish(&mut self, offset: f64)
+    {
+        if let Some(mut cue) = self.current.take()
+        {
+            cue.end = offset;
+            self.cues.push(cue);
+        }
+    }
+
+    pub fn to_vtt(&self) -> String
+    {
+        let mut s = "WEBVTT\n\n".to_string();
+        for cue in &self.cues
+        {
+            s += &format!("{} --> {}\n{}\n\n", timestamp(cue.start, '.'), timestamp(cue.end, '.'), cue.text());
+        }
+        s
+    }
+
+    pub fn to_srt(&self) -> String
+    {
+        let mut s = String::new();
+        for (i, cue) in self.cues.iter().enumerate()
+        {
+            s += &format!("{}\n{} --> {}\n{}\n\n", i + 1, timestamp(cue.start, ','), timestamp(cue.end, ','), cue.text());
+        }
+        s
+    }
+
+    /// Write the captions next to the video and optionally mux them into it. `video_part` is whether the video
+    /// is still under its part name, it's muxed then so it's finalised once, with the captions.
+    pub fn write(&self, video_filename: &str, subtitles: &Subtitles, video_part: bool)
+    {
+        let (extension, contents) = match subtitles.format {
+            SubtitleFormat::Vtt => ("vtt", self.to_vtt()),
+            SubtitleFormat::Srt => ("srt", self.to_srt()),
+        };
+        let plain = encryption::plain_name(video_filename);
+        let filename = match plain.rfind('.') {
+            Some(i) => format!("{}.{}", &plain[..i], extension),
+            None => format!("{}.{}", plain, extension),
+        };
+        let filename = encryption::stored_name(&filename);
+
+        // The part files are in the clear, so captions can be muxed into videos that will be encrypted
+        let part = part_filename(&filename);
+        if let Err(e) = fs::write(&part, contents)
+        {
+            write_error(&filename, &e);
+            return;
+        }
+        if subtitles.mux && video_part { mux(video_filename, &part); }
+        if let Err(e) = finalise(&filename) { write_error(&filename, &e); }
+    }
+}
+
+// Add the subtitles to the video's part file as a mov_text stream, leaving it untouched on failure
+fn mux(video_filename: &str, subtitle_filename: &str)
+{
+    let video = part_filename(video_filename);
+    let muxed = part_filename(&video);
+    let r = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i", &video, "-i", subtitle_filename])
+        .args(["-map", "0", "-map", "1", "-c", "copy", "-c:s", "mov_text", &muxed])
+        .status();
+
+    match r
+    {
+        Ok(status) if status.success() => {
+            if let Err(e) = fs::rename(&muxed, &video)
+            {
+                error!("Can't replace {} with muxed video: {}", &video, e);
+                let _ = fs::remove_file(&muxed);
+            }
+        }
+        Ok(status) => {
+            error!("ffmpeg failed to mux subtitles into {}: {}", video_filename, status);
+            let _ = fs::remove_file(&muxed);
+        }
+        Err(e) => { error!("Can't run ffmpeg to mux subtitles: {}", e); }
+    }
+}
+
+// hh:mm:ss.mmm (WebVTT) or hh:mm:ss,mmm (SRT)
+fn timestamp(seconds: f64, separator: char) -> String
+{
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}{}{:03}", ms / 3_600_000, (ms / 60_000) % 60, (ms / 1000) % 60, separator, ms % 1000)
+}
@@ -0,0 +1,25 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A path in the temp directory that nothing is at yet, unique to the name and this test run
+pub fn temp_path(name: &str) -> PathBuf
+{
+    let path = std::env::temp_dir().join(format!("security_camera_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Polls until the condition holds, returning false if it still doesn't after the timeout
+pub fn wait_for(mut condition: impl FnMut() -> bool, timeout: Duration) -> bool
+{
+    let started = Instant::now();
+    while !condition()
+    {
+        if started.elapsed() > timeout { return false; }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
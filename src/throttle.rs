@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local, NaiveTime};
+use serde::Deserialize;
+
+use crate::camera::Camera;
+use crate::events::{Event, EventSink};
+
+/// Limits on how often a sink is told of person events, set for a sink and for a camera. Camera and storage events always go through.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Throttle {
+    #[serde(default)]
+    pub cooldown_secs: u64,             // after a camera's person event goes through, its others in this time don't, apart from that event's end
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+}
+
+/// A time of day when events are still recorded but sinks aren't told of them
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuietHours {
+    pub start: String,                  // HH:MM, local time
+    pub end: String,                    // before start to run over midnight
+    #[serde(default)]
+    pub digest: bool,                   // pass the events ended in the quiet hours on as one digest when they finish, rather than dropping them
+}
+
+// Quiet hours as times
+#[derive(Debug, Clone, Copy)]
+struct Quiet {
+    start: NaiveTime,
+    end: NaiveTime,
+    digest: bool,
+}
+
+impl Quiet
+{
+    fn parse(hours: &QuietHours) -> Option<Quiet>
+    {
+        match (NaiveTime::parse_from_str(&hours.start, "%H:%M"), NaiveTime::parse_from_str(&hours.end, "%H:%M")) {
+            (Ok(start), Ok(end)) => Some(Quiet { start, end, digest: hours.digest }),
+            _ => {
+                warn!("Ignoring quiet hours {}-{}, the times should be HH:MM", &hours.start, &hours.end);
+                None
+            }
+        }
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool
+    {
+        if self.start <= self.end { self.start <= time && time < self.end } else { time >= self.start || time < self.end }
+    }
+}
+
+// The limits for one camera, its own and the sink's together
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    cooldown: Duration,
+    quiet: Vec<Quiet>,
+}
+
+impl Limits
+{
+    fn new(throttles: &[&Throttle]) -> Limits
+    {
+        Limits {
+            cooldown: Duration::from_secs(throttles.iter().map(|t| t.cooldown_secs).max().unwrap_or(0)),
+            quiet: throttles.iter().flat_map(|t| &t.quiet_hours).filter_map(Quiet::parse).collect(),
+        }
+    }
+
+    fn quiet(&self, time: NaiveTime) -> Option<Quiet>
+    {
+        self.quiet.iter().find(|q| q.contains(time)).copied()
+    }
+}
+
+/// Passes events on to a sink within its limits and those of the cameras
+pub struct Throttled {
+    sink: Box<dyn EventSink>,
+    limits: Limits,                     // for cameras without their own
+    cameras: HashMap<String, Limits>,
+    last_passed: HashMap<String, Instant>,      // when each camera's last person event went through
+    started: HashSet<String>,           // events whose start went through and whose end hasn't yet
+    digest: Vec<Event>,
+    digest_start: Option<DateTime<Local>>,
+}
+
+impl Throttled
+{
+    pub fn new(sink: Box<dyn EventSink>, throttle: &Throttle, cameras: &[Camera]) -> Throttled
+    {
+        let camera_limits = cameras.iter()
+            .filter_map(|c| c.throttle.as_ref().map(|t| (c.name.clone(), Limits::new(&[throttle, t]))))
+            .collect();
+        Throttled {
+            sink,
+            limits: Limits::new(&[throttle]),
+            cameras: camera_limits,
+            last_passed: HashMap::new(),
+            started: HashSet::new(),
+            digest: Vec::new(),
+            digest_start: None,
+        }
+    }
+
+    // Whether a person event goes through now
+    fn pass(&mut self, event: &Event, camera: &str, limits: &Limits) -> bool
+    {
+        let cooled = self.last_passed.get(camera).is_none_or(|t| t.elapsed() >= limits.cooldown);
+        let passed = match event {
+            Event::Started { event_id, .. } => {
+                if cooled { self.started.insert(event_id.clone()); }
+                cooled
+            }
+            Event::BestImageUpdated { event_id, .. } => { return self.started.contains(event_id); }
+            Event::Ended { event_id, .. } => self.started.remove(event_id) || cooled,
+            _ => true,
+        };
+        if passed { self.last_passed.insert(camera.to_string(), Instant::now()); }
+        else { debug!("{}: Holding back {} for the cooldown", self.sink.name(), event.name()); }
+        passed
+    }
+
+    // Send the digest once the quiet hours are over
+    fn send_digest(&mut self, now: DateTime<Local>)
+    {
+        let start = match self.digest_start {
+            Some(start) => start,
+            None => { return; }
+        };
+        let still_quiet = self.digest.iter().filter_map(|e| e.camera()).any(|c| self.cameras.get(c).unwrap_or(&self.limits).quiet(now.time()).is_some());
+        if still_quiet { return; }
+
+        let events = std::mem::take(&mut self.digest);
+        self.digest_start = None;
+        info!("{}: Sending a digest of {} events from the quiet hours", self.sink.name(), events.len());
+        self.sink.handle(&Event::Digest { start: start.to_rfc3339(), end: now.to_rfc3339(), events });
+    }
+}
+
+impl EventSink for Throttled
+{
+    fn name(&self) -> &str { self.sink.name() }
+
+    fn handle(&mut self, event: &Event)
+    {
+        let camera = match event {
+            Event::Started { camera, .. } | Event::BestImageUpdated { camera, .. } | Event::Ended { camera, .. } => camera.as_str(),
+            _ => {
+                self.sink.handle(event);
+                return;
+            }
+        };
+        let now = Local::now();
+        let limits = self.cameras.get(camera).unwrap_or(&self.limits).clone();
+
+        if let Some(quiet) = limits.quiet(now.time())
+        {
+            // The ends are enough for the digest, they have the video and the best image
+            if let Event::Ended { event_id, .. } = event
+            {
+                self.started.remove(event_id);
+                if quiet.digest
+                {
+                    self.digest_start.get_or_insert(now);
+                    self.digest.push(event.clone());
+                }
+            }
+            return;
+        }
+        if self.pass(event, camera, &limits) { self.sink.handle(event); }
+        self.send_digest(now);
+    }
+
+    fn tick(&mut self)
+    {
+        self.send_digest(Local::now());
+        self.sink.tick();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // A sink keeping the names of the events it's given
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl EventSink for Recorder
+    {
+        fn name(&self) -> &str { "recorder" }
+
+        fn handle(&mut self, event: &Event)
+        {
+            let name = match event {
+                Event::Digest { events, .. } => format!("digest of {}", events.len()),
+                _ => format!("{} {}", event.name(), event.camera().unwrap_or_default()),
+            };
+            self.0.lock().unwrap().push(name);
+        }
+    }
+
+    fn throttled(throttle: &Throttle) -> (Throttled, Arc<Mutex<Vec<String>>>)
+    {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        (Throttled::new(Box::new(Recorder(Arc::clone(&handled))), throttle, &[]), handled)
+    }
+
+    fn at(time: &str) -> NaiveTime
+    {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn quiet(start: &str, end: &str) -> QuietHours
+    {
+        QuietHours { start: start.to_string(), end: end.to_string(), digest: true }
+    }
+
+    fn started(camera: &str, event_id: &str) -> Event
+    {
+        Event::Started {
+            camera: camera.to_string(), event_id: event_id.to_string(), time: String::new(), first_image: String::new(),
+            zones: vec![], label: "person".to_string(), score: 0.9,
+        }
+    }
+
+    fn best(camera: &str, event_id: &str) -> Event
+    {
+        Event::BestImageUpdated { camera: camera.to_string(), event_id: event_id.to_string(), part: 1, time: String::new(), score: 0.9 }
+    }
+
+    fn ended(camera: &str, event_id: &str) -> Event
+    {
+        Event::Ended {
+            camera: camera.to_string(), event_id: event_id.to_string(), part: 1, start: String::new(), end: String::new(),
+            video: String::new(), best_image: String::new(), preview_gif: String::new(), contact_sheet: String::new(),
+            sidecar: String::new(), zones: vec![], labels: vec![], max_score: 0.9,
+        }
+    }
+
+    #[test]
+    fn quiet_hours_in_the_day()
+    {
+        let hours = Quiet::parse(&quiet("09:00", "17:30")).unwrap();
+        assert!(!hours.contains(at("08:59")));
+        assert!(hours.contains(at("09:00")));
+        assert!(hours.contains(at("17:29")));
+        assert!(!hours.contains(at("17:30")));
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight()
+    {
+        let hours = Quiet::parse(&quiet("23:00", "07:00")).unwrap();
+        assert!(hours.contains(at("23:00")));
+        assert!(hours.contains(at("00:00")));
+        assert!(hours.contains(at("06:59")));
+        assert!(!hours.contains(at("07:00")));
+        assert!(!hours.contains(at("12:00")));
+        assert!(!hours.contains(at("22:59")));
+    }
+
+    #[test]
+    fn limits_take_the_longest_cooldown_and_every_quiet_hours()
+    {
+        let sink = Throttle { cooldown_secs: 30, quiet_hours: vec![quiet("01:00", "02:00"), quiet("25:00", "02:00")] };
+        let camera = Throttle { cooldown_secs: 60, quiet_hours: vec![quiet("03:00", "04:00")] };
+        let limits = Limits::new(&[&sink, &camera]);
+        assert_eq!(limits.cooldown, Duration::from_secs(60));
+        assert_eq!(limits.quiet.len(), 2);
+        assert!(limits.quiet(at("01:30")).is_some());
+        assert!(limits.quiet(at("03:30")).is_some());
+        assert!(limits.quiet(at("02:30")).is_none());
+    }
+
+    #[test]
+    fn pass_holds_back_other_events_in_the_cooldown()
+    {
+        let (mut throttled, _) = throttled(&Throttle::default());
+        let limits = Limits { cooldown: Duration::from_secs(60), quiet: vec![] };
+        assert!(throttled.pass(&started("Door", "A"), "Door", &limits));
+        assert!(throttled.pass(&best("Door", "A"), "Door", &limits));
+        assert!(!throttled.pass(&started("Door", "B"), "Door", &limits));
+        assert!(!throttled.pass(&best("Door", "B"), "Door", &limits));
+        assert!(!throttled.pass(&ended("Door", "B"), "Door", &limits));
+        assert!(throttled.pass(&started("Garage", "C"), "Garage", &limits));
+
+        // The end of an event whose start went through always does
+        assert!(throttled.pass(&ended("Door", "A"), "Door", &limits));
+        assert!(!throttled.pass(&ended("Door", "A"), "Door", &limits));
+    }
+
+    #[test]
+    fn pass_lets_everything_through_without_a_cooldown()
+    {
+        let (mut throttled, _) = throttled(&Throttle::default());
+        let limits = Limits::default();
+        assert!(throttled.pass(&started("Door", "A"), "Door", &limits));
+        assert!(throttled.pass(&started("Door", "B"), "Door", &limits));
+        assert!(throttled.pass(&ended("Door", "B"), "Door", &limits));
+    }
+
+    #[test]
+    fn quiet_hours_gather_the_ends_into_a_digest()
+    {
+        let now = Local::now();
+        let minutes = |m: i64| (now + chrono::Duration::minutes(m)).format("%H:%M").to_string();
+        let throttle = Throttle { cooldown_secs: 0, quiet_hours: vec![quiet(&minutes(-2), &minutes(2))] };
+        let (mut throttled, handled) = throttled(&throttle);
+
+        throttled.handle(&started("Door", "A"));
+        throttled.handle(&ended("Door", "A"));
+        throttled.handle(&ended("Garage", "B"));
+        throttled.handle(&Event::CameraOffline { camera: "Door".to_string(), reason: String::new() });
+        assert_eq!(*handled.lock().unwrap(), ["camera_offline Door"]);
+
+        throttled.send_digest(now);
+        assert_eq!(handled.lock().unwrap().len(), 1);
+        throttled.send_digest(now + chrono::Duration::minutes(10));
+        assert_eq!(*handled.lock().unwrap(), ["camera_offline Door", "digest of 2"]);
+    }
+}
@@ -0,0 +1,269 @@
+use chrono::{DateTime, Datelike, Local};
+use serde::Deserialize;
+
+use opencv::{prelude::*, Result};
+use opencv::core::{Point, Scalar, Size};
+use opencv::imgproc::{circle, FILLED, LINE_AA};
+
+use crate::camera::Detection;
+use crate::index;
+use crate::metadata::{SegmentMetadata, sidecar_filename};
+use crate::overlay::{original_filename, Overlay};
+use crate::paths;
+use crate::retention::Category;
+use crate::recorder::{close_writer, open_writer, PacedWriter, TimedFrame, write_frame};
+use crate::storage::write_error;
+
+/// How long each timelapse file covers, files roll over as the clock crosses a period boundary
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hourly,
+    Daily,
+    Minutes(u32),
+}
+
+/// When timelapse frames are captured and how the files are named
+#[derive(Debug, Deserialize, Clone)]
+pub struct TimelapseSchedule {
+    #[serde(default = "default_period")]
+    pub period: Period,
+    #[serde(default = "default_capture_seconds")]
+    pub capture_seconds: f64,       // stream seconds between captured frames
+    #[serde(default = "default_playback_fps")]
+    pub playback_fps: f64,
+    #[serde(default = "default_filename")]
+    pub filename: String,           // template, see expand_template()
+    pub adaptive: Option<Adaptive>,
+}
+
+/// Sample quiet periods sparsely and periods with people in the zones densely, replaces capture_seconds
+#[derive(Debug, Deserialize, Clone)]
+pub struct Adaptive {
+    #[serde(default = "default_quiet_seconds")]
+    pub quiet_seconds: f64,
+    #[serde(default = "default_active_seconds")]
+    pub active_seconds: f64,
+    #[serde(default = "default_hold_seconds")]
+    pub hold_seconds: f64,          // stay dense this long after the last detection
+    #[serde(default)]
+    pub marker: bool,               // mark frames with a person in them
+}
+
+fn default_period() -> Period { Period::Hourly }
+fn default_capture_seconds() -> f64 { 1.0 }
+fn default_playback_fps() -> f64 { 1.5 }
+fn default_filename() -> String { "{camera}{timestamp}".to_string() }
+fn default_quiet_seconds() -> f64 { 10.0 }
+fn default_active_seconds() -> f64 { 1.0 }
+fn default_hold_seconds() -> f64 { 30.0 }
+
+impl Default for TimelapseSchedule
+{
+    fn default() -> Self
+    {
+        TimelapseSchedule {
+            period: default_period(),
+            capture_seconds: default_capture_seconds(),
+            playback_fps: default_playback_fps(),
+            filename: default_filename(),
+            adaptive: None,
+        }
+    }
+}
+
+impl Period
+{
+    // Number of the period containing a local time, this changes as a boundary is crossed
+    fn index(&self, time: &DateTime<Local>) -> i64
+    {
+        let t = time.naive_local();
+        match self {
+            Period::Hourly => t.timestamp() / 3600,
+            Period::Daily => t.date().num_days_from_ce() as i64,
+            Period::Minutes(n) => t.timestamp() / (60 * (*n).max(1) as i64),
+        }
+    }
+}
+
+/// Records a camera's timelapse files, rolling over to a new file each period
+pub struct TimelapseRecorder {
+    camera: String,
+    schedule: TimelapseSchedule,
+    overlay: Option<Overlay>,
+    size: Size,
+    pub filename: String,
+    writer: Option<PacedWriter>,
+    original: Option<PacedWriter>,   // unannotated frames if the overlay keeps the originals
+    metadata: Option<SegmentMetadata>,
+    period: i64,
+    last_capture: Option<f64>,
+    last_active: Option<f64>,
+}
+
+impl TimelapseRecorder
+{
+    pub fn start(camera: &str, schedule: &TimelapseSchedule, overlay: &Option<Overlay>, size: Size, time: &DateTime<Local>) -> TimelapseRecorder
+    {
+        let mut timelapse = TimelapseRecorder {
+            camera: camera.to_string(),
+            schedule: schedule.clone(),
+            overlay: overlay.clone(),
+            size,
+            filename: String::new(),
+            writer: None,
+            original: None,
+            metadata: None,
+            period: schedule.period.index(time),
+            last_capture: None,
+            last_active: None,
+        };
+        timelapse.open(time);
+        timelapse
+    }
+
+    // Stream milliseconds between captures
+    fn interval(&self, pts: f64) -> f64
+    {
+        let seconds = match &self.schedule.adaptive {
+            Some(adaptive) => {
+                let active = self.last_active.is_some_and(|t| pts - t <= adaptive.hold_seconds * 1000.0);
+                if active { adaptive.active_seconds } else { adaptive.quiet_seconds }
+            }
+            None => self.schedule.capture_seconds,
+        };
+        seconds.max(0.001) * 1000.0
+    }
+
+    fn open(&mut self, time: &DateTime<Local>)
+    {
+        // Adaptive files change density so frames are written as they're captured rather than paced in real time
+        let interval = match self.schedule.adaptive {
+            Some(_) => 0.0,
+            None => self.interval(0.0),
+        };
+        let fps = self.schedule.playback_fps;
+        let name = format!("{}.mp4", expand_template(&self.schedule.filename, &self.camera, time));
+        self.filename = paths::file(Category::Timelapse, &self.camera, time, &name);
+        self.writer = open_writer(&self.filename, fps, self.size, interval);
+        self.original = match &self.overlay {
+            Some(overlay) if overlay.keep_original => open_writer(&original_filename(&self.filename), fps, self.size, interval),
+            _ => None
+        };
+    }
+
+    /// Is a frame due at this timestamp ? The frame's detections decide the density of adaptive timelapses.
+    pub fn due(&mut self, pts: f64, detections: &[Detection]) -> bool
+    {
+        if present(detections) { self.last_active = Some(pts); }
+        match self.last_capture {
+            Some(last) => pts - last >= self.interval(pts),
+            None => true,
+        }
+    }
+
+    pub fn write(&mut self, f: TimedFrame)
+    {
+        let pts = f.pts;
+        self.add_metadata(&f.time);
+        let marker = self.schedule.adaptive.as_ref().is_some_and(|a| a.marker) && present(&f.detections);
+        let (mut frame, original) = match &self.overlay {
+            Some(overlay) => (overlay.render(&self.camera, &f), Some(f.frame)),
+            None => (f.frame, None),
+        };
+        if marker
+        {
+            if let Err(e) = draw_marker(&mut frame) { error!("{}: Error drawing timelapse marker: {}", &self.camera, e); }
+        }
+        write_frame(&mut self.writer, frame, pts, &self.filename);
+        if let Some(original) = original { write_frame(&mut self.original, original, pts, &original_filename(&self.filename)); }
+
+        // Stay on the capture schedule unless we've fallen a whole interval behind
+        let interval = self.interval(pts);
+        self.last_capture = Some(match self.last_capture {
+            Some(last) if pts - last < 2.0 * interval => last + interval,
+            _ => pts,
+        });
+    }
+
+    // Times of the frames in the file, so footage can be found by time
+    fn add_metadata(&mut self, time: &DateTime<Local>)
+    {
+        let adaptive = self.schedule.adaptive.is_some();
+        let interval = match adaptive {
+            true => 0.0,
+            false => self.interval(0.0) / 1000.0,
+        };
+        if self.metadata.is_none()
+        {
+            self.metadata = Some(SegmentMetadata::new(&self.camera, &self.filename, time, self.schedule.playback_fps, interval));
+        }
+        if let Some(metadata) = &mut self.metadata
+        {
+            metadata.end = time.to_rfc3339();
+            if adaptive { metadata.frame_times.push(time.to_rfc3339()); }
+        }
+    }
+
+    /// Start a new file if a period boundary has been crossed, returns the name of the file just closed
+    pub fn rollover(&mut self, time: &DateTime<Local>) -> Option<String>
+    {
+        let period = self.schedule.period.index(time);
+        if period == self.period { return None; }
+
+        self.period = period;
+        let closed = self.filename.clone();
+        self.close();
+        self.open(time);
+        Some(closed)
+    }
+
+    pub fn close(&mut self)
+    {
+        if let (Some(metadata), Some(w)) = (&mut self.metadata, &self.writer) { metadata.gaps = w.gaps().to_vec(); }
+        close_writer(&mut self.writer, &self.filename);
+        close_writer(&mut self.original, &original_filename(&self.filename));
+        if let Some(metadata) = self.metadata.take()
+        {
+            let sidecar = sidecar_filename(&self.filename);
+            if let Err(e) = metadata.write(&sidecar) { write_error(&sidecar, &e); }
+            index::record_segment("timelapse", &metadata);
+        }
+    }
+}
+
+// The file is finished however the camera stops, e.g. the stream failing, rather than left as a part file
+impl Drop for TimelapseRecorder
+{
+    fn drop(&mut self)
+    {
+        self.close();
+    }
+}
+
+// A person inside the camera's boundary
+fn present(detections: &[Detection]) -> bool
+{
+    detections.iter().any(|det| det.inside)
+}
+
+// Red dot in the top right corner
+fn draw_marker(frame: &mut Mat) -> Result<()>
+{
+    let h = frame.rows();
+    let radius = (h / 30).max(4);
+    let centre = Point::new(frame.cols() - 2 * radius, 2 * radius);
+    circle(frame, centre, radius, Scalar::from((255.0, 255.0, 255.0)), FILLED, LINE_AA, 0)?;
+    circle(frame, centre, radius - 2, Scalar::from((32.0, 32.0, 224.0)), FILLED, LINE_AA, 0)
+}
+
+/// Fill in a filename template. `{camera}`, `{timestamp}` (20230519-101500), `{date}` (20230519) and `{time}` (101500)
+/// are replaced, the result is sanitised by paths::file() so the file stays in its directory.
+pub fn expand_template(template: &str, camera: &str, time: &DateTime<Local>) -> String
+{
+    template
+        .replace("{camera}", camera)
+        .replace("{timestamp}", &time.format("%Y%m%d-%H%M%S").to_string())
+        .replace("{date}", &time.format("%Y%m%d").to_string())
+        .replace("{time}", &time.format("%H%M%S").to_string())
+}
@@ -0,0 +1,85 @@
+use opencv::core::Rect;
+
+use crate::camera::Detection;
+
+const MIN_IOU: f32 = 0.3;       // overlap needed to continue a track
+const MAX_MISSED: u32 = 15;     // frames a track survives without a detection
+
+struct Track {
+    id: u32,
+    rect: Rect,
+    missed: u32,
+}
+
+/// Gives detections a track ID that stays the same while the person moves through the frame.
+/// Greedy matching on box overlap, which is plenty for the handful of people in a camera view.
+pub struct Tracker {
+    tracks: Vec<Track>,
+    next_id: u32,
+}
+
+impl Tracker
+{
+    pub fn new() -> Tracker
+    {
+        Tracker {
+            tracks: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn update(&mut self, detections: &mut Vec<Detection>)
+    {
+        let mut matched = vec![false; self.tracks.len()];
+
+        for det in detections.iter_mut()
+        {
+            let mut best = None;
+            let mut best_iou = MIN_IOU;
+            for (i, track) in self.tracks.iter().enumerate()
+            {
+                let overlap = iou(&track.rect, &det.rect);
+                if !matched[i] && overlap > best_iou
+                {
+                    best = Some(i);
+                    best_iou = overlap;
+                }
+            }
+
+            match best
+            {
+                Some(i) => {
+                    matched[i] = true;
+                    self.tracks[i].rect = det.rect;
+                    self.tracks[i].missed = 0;
+                    det.track_id = self.tracks[i].id;
+                }
+                None => {
+                    det.track_id = self.next_id;
+                    self.tracks.push(Track { id: self.next_id, rect: det.rect, missed: 0 });
+                    matched.push(true);
+                    self.next_id += 1;
+                }
+            }
+        }
+
+        for (i, track) in self.tracks.iter_mut().enumerate()
+        {
+            if !matched[i] { track.missed += 1; }
+        }
+        self.tracks.retain(|t| t.missed <= MAX_MISSED);
+    }
+}
+
+fn iou(a: &Rect, b: &Rect) -> f32
+{
+    let x1 = a.x.max(b.x);
+    let y1 = a.y.max(b.y);
+    let x2 = (a.x + a.width).min(b.x + b.width);
+    let y2 = (a.y + a.height).min(b.y + b.height);
+    if x2 <= x1 || y2 <= y1 { return 0.0; }
+
+    let intersection = ((x2 - x1) * (y2 - y1)) as f32;
+    let union = (a.width * a.height + b.width * b.height) as f32 - intersection;
+    if union > 0.0 { intersection / union } else { 0.0 }
+}
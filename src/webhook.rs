@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::encryption;
+use crate::events::{Event, EventSink};
+use crate::hooks::{environment, fill};
+use crate::paths::{self, sanitise};
+use crate::throttle::Throttle;
+
+/// Posts events to a URL, as JSON or as multipart with the event's image attached
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhook {
+    pub name: String,               // names its queue directory
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,        // event names to post, all of them if empty
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<String>,       // template, fields are filled in JSON escaped. The event's JSON if not set.
+    #[serde(default)]
+    pub multipart: bool,            // send the body as the `payload` part, with the best or first image as the `image` part
+    pub secret: Option<String>,     // signs each request with HMAC-SHA256 of its body in X-Signature-256
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_backoff_secs")]
+    pub backoff_secs: u64,          // wait after a failure, doubling each time up to max_backoff_secs
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,          // the oldest events are dropped beyond this
+    #[serde(default)]
+    pub throttle: Throttle,
+}
+
+fn default_timeout_secs() -> u64 { 10 }
+fn default_backoff_secs() -> u64 { 5 }
+fn default_max_backoff_secs() -> u64 { 600 }
+fn default_max_queued() -> usize { 1000 }
+
+const BOUNDARY: &str = "security-camera-boundary-7MA4YWxkTrZu0gW";
+
+/// Queues events on disk and posts them from its own thread, so they survive the network or the device going down
+pub struct WebhookSink {
+    webhook: Webhook,
+    queue: PathBuf,
+    wake: Sender<()>,
+    seq: u64,
+}
+
+impl WebhookSink
+{
+    pub fn new(webhook: &Webhook) -> Result<WebhookSink, Box<dyn Error>>
+    {
+        let queue = Path::new(paths::root()).join("queue").join(sanitise(&webhook.name));
+        fs::create_dir_all(&queue)?;
+        let queued = queued(&queue).len();
+        if queued > 0 { info!("Webhook {} has {} events queued.", &webhook.name, queued); }
+
+        let (wake, rx) = mpsc::channel();
+        let w = webhook.clone();
+        let q = queue.clone();
+        thread::spawn(move || deliver(&w, &q, rx));
+        Ok(WebhookSink { webhook: webhook.clone(), queue, wake, seq: 0 })
+    }
+
+    fn enqueue(&mut self, event: &Event) -> Result<(), Box<dyn Error>>
+    {
+        // Named to sort in the order they were queued
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        self.seq += 1;
+        let filename = self.queue.join(format!("{:015}-{:06}.json", now, self.seq % 1_000_000));
+        let part = filename.with_extension("part");
+        fs::write(&part, serde_json::to_string(event)?)?;
+        fs::rename(&part, &filename)?;
+
+        let mut files = queued(&self.queue);
+        while files.len() > self.webhook.max_queued
+        {
+            let oldest = files.remove(0);
+            warn!("Webhook {} queue is full, dropping {}", &self.webhook.name, oldest.display());
+            let _ = fs::remove_file(oldest);
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for WebhookSink
+{
+    fn name(&self) -> &str { &self.webhook.name }
+
+    fn handle(&mut self, event: &Event)
+    {
+        if !self.webhook.events.is_empty() && !event.is_one_of(&self.webhook.events) { return; }
+        match self.enqueue(event) {
+            Ok(_) => { let _ = self.wake.send(()); }
+            Err(e) => { error!("Can't queue {} for webhook {}: {}", event.name(), &self.webhook.name, e); }
+        }
+    }
+}
+
+// Queued events, oldest first
+fn queued(queue: &Path) -> Vec<PathBuf>
+{
+    let mut files: Vec<PathBuf> = match fs::read_dir(queue) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).filter(|p| p.extension().is_some_and(|e| e == "json")).collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files
+}
+
+// Post the queue in order whenever an event is added, backing off while the server can't be reached
+fn deliver(webhook: &Webhook, queue: &Path, wake: Receiver<()>)
+{
+    let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(webhook.timeout_secs)).build();
+    let mut backoff = webhook.backoff_secs;
+    loop
+    {
+        match post_queued(webhook, &agent, queue) {
+            Ok(_) => {
+                backoff = webhook.backoff_secs;
+                if wake.recv().is_err() { return; }
+            }
+            Err(e) => {
+                warn!("Webhook {} failed, will retry in {}s: {}", &webhook.name, backoff, e);
+                // Events arriving while backing off don't cut the wait short
+                let until = Instant::now() + Duration::from_secs(backoff);
+                while let Some(left) = until.checked_duration_since(Instant::now())
+                {
+                    if let Err(RecvTimeoutError::Disconnected) = wake.recv_timeout(left) { return; }
+                }
+                backoff = (backoff * 2).min(webhook.max_backoff_secs);
+            }
+        }
+    }
+}
+
+fn post_queued(webhook: &Webhook, agent: &ureq::Agent, queue: &Path) -> Result<(), Box<dyn Error>>
+{
+    for file in queued(queue)
+    {
+        let event: Event = match fs::read_to_string(&file).map_err(|e| e.to_string()).and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string())) {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Dropping unreadable queued event {}: {}", file.display(), e);
+                let _ = fs::remove_file(&file);
+                continue;
+            }
+        };
+
+        match post(webhook, agent, &event).map_err(|e| *e) {
+            Ok(status) => { info!("Webhook {} posted {}, {}", &webhook.name, event.name(), status); }
+            // The server won't take it however many times it's sent
+            Err(ureq::Error::Status(status, _)) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                error!("Webhook {} rejected {} with {}, dropping it", &webhook.name, event.name(), status);
+            }
+            Err(e) => { return Err(e.into()); }
+        }
+        fs::remove_file(&file)?;
+    }
+    Ok(())
+}
+
+fn post(webhook: &Webhook, agent: &ureq::Agent, event: &Event) -> Result<u16, Box<ureq::Error>>
+{
+    let payload = match &webhook.body {
+        Some(template) => fill(template, &environment(event), json_escape),
+        None => serde_json::to_string(event).unwrap_or_default(),
+    };
+    let (content_type, body) = match webhook.multipart {
+        true => (format!("multipart/form-data; boundary={}", BOUNDARY), multipart(&payload, image(event))),
+        false => ("application/json".to_string(), payload.into_bytes()),
+    };
+
+    let mut request = agent.post(&webhook.url).set("Content-Type", &content_type);
+    for (name, value) in &webhook.headers { request = request.set(name, value); }
+    if let Some(secret) = &webhook.secret
+    {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key length");
+        mac.update(&body);
+        request = request.set("X-Signature-256", &format!("sha256={}", hex::encode(mac.finalize().into_bytes())));
+    }
+    Ok(request.send_bytes(&body).map_err(Box::new)?.status())
+}
+
+// The image worth attaching to an event, if it has one
+fn image(event: &Event) -> Option<&str>
+{
+    let image = match event {
+        Event::Started { first_image, .. } => first_image,
+        Event::Ended { best_image, .. } => best_image,
+        _ => { return None; }
+    };
+    if image.is_empty() { None } else { Some(image) }
+}
+
+fn multipart(payload: &str, image: Option<&str>) -> Vec<u8>
+{
+    let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"payload\"\r\nContent-Type: application/json\r\n\r\n{}\r\n", BOUNDARY, payload).into_bytes();
+    if let Some(image) = image
+    {
+        match fs::read(image) {
+            Ok(data) => {
+                let name = Path::new(image).file_name().unwrap_or_default().to_string_lossy().to_string();
+                let content_type = if encryption::is_encrypted(image) { "application/octet-stream" } else { "image/jpeg" };
+                body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n", BOUNDARY, name, content_type).into_bytes());
+                body.extend(data);
+                body.extend(b"\r\n");
+            }
+            Err(e) => { warn!("Can't attach {}: {}", image, e); }
+        }
+    }
+    body.extend(format!("--{}--\r\n", BOUNDARY).into_bytes());
+    body
+}
+
+// A value to go inside a JSON string
+fn json_escape(value: &str) -> String
+{
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use crate::test_util::{temp_path, wait_for};
+
+    fn webhook(url: &str, extra: serde_json::Value) -> Webhook
+    {
+        let mut config = serde_json::json!({ "name": "test", "url": url, "backoff_secs": 1 });
+        if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) { config.extend(extra.clone()); }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn online(camera: &str) -> Event
+    {
+        Event::CameraOnline { camera: camera.to_string() }
+    }
+
+    // A server answering each request with the next status, passing on the head and body it was sent
+    fn server(statuses: Vec<u16>) -> (String, Receiver<(String, Vec<u8>)>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop
+                {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" { break; }
+                    head += &line;
+                }
+                let length = head.lines()
+                    .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                tx.send((head, body)).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn json_escape_keeps_the_string_valid()
+    {
+        assert_eq!(json_escape("Door"), "Door");
+        assert_eq!(json_escape("say \"hi\"\\\n"), "say \\\"hi\\\"\\\\\\n");
+
+        let body = fill(r#"{"text": "{camera} is {event}"}"#, &environment(&online("Door \"1\"\n")), json_escape);
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["text"], "Door \"1\"\n is camera_online");
+    }
+
+    #[test]
+    fn multipart_has_the_payload_and_image()
+    {
+        let image = temp_path("webhook_image.jpg");
+        fs::write(&image, b"jpeg data").unwrap();
+        let body = String::from_utf8(multipart("{}", Some(&image.to_string_lossy()))).unwrap();
+        let parts: Vec<&str> = body.split(&format!("--{}", BOUNDARY)).collect();
+        assert_eq!(parts.len(), 4);
+        assert!(parts[1].contains("name=\"payload\"") && parts[1].ends_with("\r\n\r\n{}\r\n"));
+        assert!(parts[2].contains(&format!("name=\"image\"; filename=\"{}\"", image.file_name().unwrap().to_string_lossy())));
+        assert!(parts[2].contains("Content-Type: image/jpeg") && parts[2].ends_with("\r\n\r\njpeg data\r\n"));
+        assert_eq!(parts[3], "--\r\n");
+        let _ = fs::remove_file(&image);
+
+        // An image that can't be read is left out
+        let body = String::from_utf8(multipart("{}", Some("/nonexistent/image.jpg"))).unwrap();
+        assert_eq!(body.matches(BOUNDARY).count(), 2);
+        assert!(!body.contains("name=\"image\""));
+    }
+
+    #[test]
+    fn post_sends_the_filled_body_signed()
+    {
+        let (url, requests) = server(vec![200]);
+        let webhook = webhook(&url, serde_json::json!({
+            "headers": { "X-Token": "abc" },
+            "body": "{\"text\": \"{camera}\"}",
+            "secret": "key",
+        }));
+        let agent = ureq::AgentBuilder::new().build();
+        assert_eq!(post(&webhook, &agent, &online("Door")).unwrap(), 200);
+
+        let (head, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(body, br#"{"text": "Door"}"#);
+        let head = head.to_lowercase();
+        assert!(head.starts_with("post /hook http/1.1\r\n"));
+        assert!(head.contains("content-type: application/json\r\n"));
+        assert!(head.contains("x-token: abc\r\n"));
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"key").unwrap();
+        mac.update(&body);
+        assert!(head.contains(&format!("x-signature-256: sha256={}\r\n", hex::encode(mac.finalize().into_bytes()))));
+    }
+
+    #[test]
+    fn queued_events_are_posted_in_order_after_a_failure()
+    {
+        let (url, requests) = server(vec![503, 200, 400, 200]);
+        let queue = temp_path("webhook_queue");
+        fs::create_dir_all(&queue).unwrap();
+        let (wake, rx) = mpsc::channel();
+        let mut sink = WebhookSink { webhook: webhook(&url, serde_json::json!({})), queue: queue.clone(), wake, seq: 0 };
+        for camera in ["A", "B", "C"] { sink.enqueue(&online(camera)).unwrap(); }
+
+        let (w, q) = (sink.webhook.clone(), queue.clone());
+        let delivery = thread::spawn(move || deliver(&w, &q, rx));
+        wait_for(|| queued(&queue).is_empty(), Duration::from_secs(10));
+        drop(sink);
+        delivery.join().unwrap();
+
+        // A is tried again after the 503, B is dropped as the server rejects it
+        let cameras: Vec<String> = (0..4).filter_map(|_| requests.recv_timeout(Duration::from_secs(5)).ok())
+            .map(|(_, body)| serde_json::from_slice::<serde_json::Value>(&body).unwrap()["camera"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(cameras, ["A", "A", "B", "C"]);
+        assert!(queued(&queue).is_empty());
+        let _ = fs::remove_dir_all(&queue);
+    }
+}